@@ -5,7 +5,7 @@
 extern crate hyper;
 extern crate orca;
 
-use orca::{App, InstalledAppError, ResponseGenFn, Scopes};
+use orca::{App, InstalledAppError, ResponseGenFn, Scopes, TokenDuration};
 
 use hyper::{Body, Response};
 
@@ -37,7 +37,7 @@ fn main() {
 	let scopes = Scopes::all();
 
 	let mut reddit = App::new("orca_installed_app_example", "1.0", "/u/IntrepidPig").unwrap();
-	reddit.authorize_installed_app(&id, &redirect, response_gen, &scopes).unwrap();
+	reddit.authorize_installed_app(&id, &redirect, response_gen, &scopes, TokenDuration::Permanent).unwrap();
 
 	let user = reddit.get_self().unwrap();
 	println!("Got data: {}", user);
@@ -1,16 +1,11 @@
 //! This example lets you PM someone from the command line. It also requires setting up your own script
-//! app at [Reddit](https://www.reddit.com/prefs/apps). This one loads the variables from the environment.
+//! app at [Reddit](https://www.reddit.com/prefs/apps). This one loads the client id, secret and user
+//! agent from a praw.ini file or `praw_` environment variables, and asks for the login if it isn't
+//! there too.
 
 extern crate orca;
 
-use orca::App;
-
-fn get_client_data() -> (String, String) {
-	use std::env;
-	let id = env::var("ORCA_CLIENT_ID").expect("ORCA_CLIENT_ID must be set");
-	let secret = env::var("ORCA_CLIENT_SECRET").expect("ORCA_CLIENT_SECRET must be set");
-	(id, secret)
-}
+use orca::Config;
 
 fn input(query: &str) -> String {
 	use std::io::Write;
@@ -23,13 +18,14 @@ fn input(query: &str) -> String {
 }
 
 fn main() {
-	let (id, secret) = get_client_data();
-	println!("Please log in.");
-	let username = input("Username: ");
-	let password = input("Password: ");
-
-	let mut reddit = App::new("orca_pm_example", "1.0", "/u/IntrepidPig").unwrap();
-	reddit.authorize_script(&id, &secret, &username, &password).unwrap();
+	let mut config = Config::load(None).unwrap();
+	if config.get("username").is_none() || config.get("password").is_none() {
+		println!("Please log in.");
+		config.set("username", &input("Username: "));
+		config.set("password", &input("Password: "));
+	}
+
+	let reddit = config.app().unwrap();
 
 	println!("Please enter the details of the message.");
 	let user = input("To: ");
@@ -2,7 +2,20 @@ use failure::Error;
 use hyper::{Body, Request};
 use json::Value;
 
-use App;
+use {App, AsyncApp, RedditFuture};
+
+impl AsyncApp {
+	/// Get info of the user currently authorized
+	///
+	/// Note: requires connection to be authorized
+	/// # Returns
+	/// A future of the json value of the user data
+	pub fn get_self(&self) -> RedditFuture<Value> {
+		let req = Request::get(self.conn.oauth_url("/api/v1/me/.json")).body(Body::empty()).unwrap();
+
+		self.send_auth(req, "identity")
+	}
+}
 
 impl App {
 	/// Get info of the user currently authorized
@@ -11,8 +24,6 @@ impl App {
 	/// # Returns
 	/// A result with the json value of the user data
 	pub fn get_self(&self) -> Result<Value, Error> {
-		let req = Request::get("https://oauth.reddit.com/api/v1/me/.json").body(Body::empty()).unwrap();
-
-		self.conn.run_auth_request(req)
+		self.block_on(self.inner.get_self())
 	}
 }
@@ -1,10 +1,204 @@
 use std::sync::Arc;
+use std::time::Instant;
 
-use net::auth::OAuth;
-use {App, ResponseGenFn, Scopes};
+use futures::{future, stream, Future, Stream};
+
+use net::auth::{OAuth, PendingAuth, TokenDuration};
+use net::store::TokenStore;
+use net::Connection;
+use {App, AsyncApp, InstalledAppError, RedditError, RedditFuture, ResponseGenFn, Scopes};
 
 use failure::Error;
 
+impl AsyncApp {
+	/// Authorize this app as a script
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `username` - The username of the user to authorize as
+	/// * `password` - The password of the user to authorize as
+	pub fn authorize_script(&self, id: &str, secret: &str, username: &str, password: &str) -> RedditFuture<()> {
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::create_script_async(Arc::clone(&self.conn), id, secret, username, password).map(move |auth| conn.set_auth(auth)))
+	}
+
+	/// Authorize this app as a script for a user with two-factor authentication turned on
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `username` - The username of the user to authorize as
+	/// * `password` - The password of the user to authorize as
+	/// * `totp_secret` - The base32 two-factor secret of the user. A new code is generated from it
+	///   whenever the script authorizes.
+	pub fn authorize_script_with_totp(&self, id: &str, secret: &str, username: &str, password: &str, totp_secret: &str) -> RedditFuture<()> {
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::create_script_with_totp_async(Arc::clone(&self.conn), id, secret, username, password, totp_secret).map(move |auth| conn.set_auth(auth)))
+	}
+
+	/// Returns when the current token expires, if the app is authorized and the expiry is known.
+	/// The token is renewed automatically shortly before then if the authorization allows it.
+	pub fn token_expiry(&self) -> Option<Instant> {
+		self.conn.token_expiry()
+	}
+
+	/// Returns the scopes reddit granted the current authorization, if it's known
+	pub fn granted_scopes(&self) -> Option<Scopes> {
+		self.conn.auth.read().unwrap().as_ref().and_then(|auth| auth.scopes().cloned())
+	}
+
+	/// Authorize this app as an installed app with a refresh token saved from an earlier
+	/// authorization, instead of sending the user through the browser again
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `refresh_token` - The refresh token of the earlier authorization
+	pub fn authorize_with_refresh_token(&self, id: &str, refresh_token: &str) -> RedditFuture<()> {
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::from_refresh_token_async(Arc::clone(&self.conn), id, refresh_token).map(move |auth| conn.set_auth(auth)))
+	}
+
+	/// Sets where the authorization is saved whenever this app is authorized or gets a new token.
+	/// `None` stops saving it.
+	/// # Arguments
+	/// * `store` - The token store to save to
+	pub fn set_token_store(&self, store: Option<Arc<dyn TokenStore>>) {
+		self.conn.set_token_store(store);
+	}
+
+	/// Logs out by revoking the access token and refresh token of the current authorization with
+	/// reddit, so they can't be used anymore. The authorization is dropped afterwards, along with
+	/// the one saved in the token store, even if reddit couldn't be reached.
+	pub fn revoke(&self) -> RedditFuture<()> {
+		let reqs = match *self.conn.auth.read().unwrap() {
+			Some(ref auth) => auth.revoke_requests(&self.conn),
+			None => Vec::new(),
+		};
+
+		let (conn, clear_conn) = (Arc::clone(&self.conn), Arc::clone(&self.conn));
+		Box::new(stream::iter_ok(reqs).and_then(move |req| Connection::send_request(Arc::clone(&conn), req)).collect().then(move |result| {
+			clear_conn.clear_auth();
+			result.map(|_| ())
+		}))
+	}
+
+	/// Drops the current authorization without telling reddit, along with the one saved in the
+	/// token store. Its tokens stay valid until they expire, use `revoke` to invalidate them.
+	pub fn deauthorize(&self) {
+		self.conn.clear_auth();
+	}
+
+	/// Authorize this app with the authorization saved in the token store. Returns whether there
+	/// was one saved. If its token has expired, a new one is retrieved with the next request.
+	pub fn authorize_from_store(&self) -> Result<bool, Error> {
+		let auth = match self.conn.token_store() {
+			Some(store) => store.load()?,
+			None => None,
+		};
+		match auth {
+			Some(auth) => {
+				*self.conn.auth.write().unwrap() = Some(auth);
+				Ok(true)
+			}
+			None => Ok(false),
+		}
+	}
+
+	/// Authorize this app without a user, as a confidential client (a script or web app)
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	pub fn authorize_application(&self, id: &str, secret: &str) -> RedditFuture<()> {
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::create_application_async(Arc::clone(&self.conn), id, Some(secret), None).map(move |auth| conn.set_auth(auth)))
+	}
+
+	/// Authorize this app without a user, as an installed app
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `device_id` - A unique id of 20-30 characters for the device, or
+	///   `DO_NOT_TRACK_THIS_DEVICE` if the device shouldn't be tracked
+	pub fn authorize_installed_client(&self, id: &str, device_id: &str) -> RedditFuture<()> {
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::create_application_async(Arc::clone(&self.conn), id, None, Some(device_id)).map(move |auth| conn.set_auth(auth)))
+	}
+
+	/// Starts authorizing this app as an installed app without opening a browser or starting a
+	/// server, for machines without either. Returns the url the user should visit, on any device.
+	/// Once they authorize the app, reddit redirects them to `redirect`, and the url they end up
+	/// at should be passed to `complete_installed_app`.
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `redirect` - The app redirect URI registered on Reddit
+	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
+	/// * `duration` - Whether the authorization should last beyond the first token
+	pub fn begin_installed_app(&self, id: &str, redirect: &str, scopes: &Scopes, duration: TokenDuration) -> String {
+		let state = self.conn.add_pending_auth(PendingAuth {
+			id: id.to_string(),
+			secret: None,
+			redirect: redirect.to_string(),
+			duration,
+			started: Instant::now(),
+		});
+		OAuth::authorize_url(&self.conn, id, redirect, &state, scopes, duration)
+	}
+
+	/// Finishes authorizing this app as an installed app started with `begin_installed_app`. The
+	/// state in the url is checked against the one that was sent to reddit before the code is
+	/// exchanged for a token.
+	/// # Arguments
+	/// * `pasted` - The url the user was redirected to. Just the `code` in it is accepted too, but
+	///   then the state can't be checked, so only when there is a single installed app authorization
+	///   in progress.
+	pub fn complete_installed_app(&self, pasted: &str) -> RedditFuture<()> {
+		let pending = match OAuth::parse_redirect(pasted) {
+			Ok((code, Some(state))) => self.conn.take_pending_auth(&state).map(|pending| (code, pending)),
+			Ok((code, None)) => self.conn.take_only_pending_installed_auth().map(|pending| (code, pending)),
+			Err(e) => return Box::new(future::err(e.into())),
+		};
+		let (code, pending) = match pending {
+			Some(found) => found,
+			None => return Box::new(future::err(InstalledAppError::MismatchedState.into())),
+		};
+
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::from_code_async(Arc::clone(&self.conn), pending, &code).map(move |auth| conn.set_auth(auth)))
+	}
+
+	/// Starts authorizing this app as a web app. Returns the url to send the user to, after which
+	/// reddit redirects them to `redirect` with `code` and `state` query parameters that should be
+	/// passed to `complete_web_app`.
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `redirect` - The app redirect URI registered on Reddit
+	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
+	pub fn begin_web_app(&self, id: &str, secret: &str, redirect: &str, scopes: &Scopes) -> String {
+		let state = self.conn.add_pending_auth(PendingAuth {
+			id: id.to_string(),
+			secret: Some(secret.to_string()),
+			redirect: redirect.to_string(),
+			duration: TokenDuration::Permanent,
+			started: Instant::now(),
+		});
+		OAuth::authorize_url(&self.conn, id, redirect, &state, scopes, TokenDuration::Permanent)
+	}
+
+	/// Finishes authorizing this app as a web app by exchanging the code reddit redirected the user
+	/// back with for a token. Fails with `RedditError::MismatchedState` if the state wasn't handed
+	/// out by `begin_web_app`, or was already used.
+	/// # Arguments
+	/// * `code` - The `code` query parameter of the redirect
+	/// * `state` - The `state` query parameter of the redirect
+	pub fn complete_web_app(&self, code: &str, state: &str) -> RedditFuture<()> {
+		let pending = match self.conn.take_pending_auth(state) {
+			Some(pending) => pending,
+			None => return Box::new(future::err(RedditError::MismatchedState.into())),
+		};
+
+		let conn = Arc::clone(&self.conn);
+		Box::new(OAuth::from_code_async(Arc::clone(&self.conn), pending, code).map(move |auth| conn.set_auth(auth)))
+	}
+}
+
 impl App {
 	/// Authorize this app as a script
 	/// # Arguments
@@ -13,9 +207,19 @@ impl App {
 	/// * `username` - The username of the user to authorize as
 	/// * `password` - The password of the user to authorize as
 	pub fn authorize_script(&mut self, id: &str, secret: &str, username: &str, password: &str) -> Result<(), Error> {
-		let auth = OAuth::create_script(&self.conn, id, secret, username, password)?;
-		self.conn.auth = Some(auth);
-		Ok(())
+		self.block_on(self.inner.authorize_script(id, secret, username, password))
+	}
+
+	/// Authorize this app as a script for a user with two-factor authentication turned on
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `username` - The username of the user to authorize as
+	/// * `password` - The password of the user to authorize as
+	/// * `totp_secret` - The base32 two-factor secret of the user. A new code is generated from it
+	///   whenever the script authorizes.
+	pub fn authorize_script_with_totp(&mut self, id: &str, secret: &str, username: &str, password: &str, totp_secret: &str) -> Result<(), Error> {
+		self.block_on(self.inner.authorize_script_with_totp(id, secret, username, password, totp_secret))
 	}
 
 	/// Authorize this app as an installed app
@@ -24,15 +228,126 @@ impl App {
 	/// * `id` - The app id registered on Reddit
 	/// * `redirect` - The app redirect URI registered on Reddit
 	/// * `response_gen` - An optional function that generates a hyper Response to give to the user
-	/// based on the result of the authorization attempt. The signature is `(Result<String, InstalledAppError) -> Result<Response, Response>`.
-	/// The result passed in is either Ok with the code recieved, or Err with the error that occurred.
-	/// The value returned should usually be an Ok(Response), but you can return Err(Response) to indicate
-	/// that an error occurred within the function.
+	///   based on the result of the authorization attempt. The signature is `(Result<String, InstalledAppError) -> Result<Response, Response>`.
+	///   The result passed in is either Ok with the code recieved, or Err with the error that occurred.
+	///   The value returned should usually be an Ok(Response), but you can return Err(Response) to indicate
+	///   that an error occurred within the function.
 	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
-	/// as an installed app.
-	pub fn authorize_installed_app<I: Into<Option<Arc<ResponseGenFn>>>>(&mut self, id: &str, redirect: &str, response_gen: I, scopes: &Scopes) -> Result<(), Error> {
-		let auth = OAuth::create_installed_app(&self.conn, id, redirect, response_gen, scopes)?;
-		self.conn.auth = Some(auth);
+	///   as an installed app.
+	/// * `duration` - Whether the authorization should last beyond the first token. Temporary
+	///   authorizations expire after an hour, after which the app has to be authorized again.
+	pub fn authorize_installed_app<I: Into<Option<Arc<ResponseGenFn>>>>(&mut self, id: &str, redirect: &str, response_gen: I, scopes: &Scopes, duration: TokenDuration) -> Result<(), Error> {
+		let auth = OAuth::create_installed_app(self.conn(), id, redirect, response_gen, scopes, duration)?;
+		self.conn().set_auth(auth);
 		Ok(())
 	}
+
+	/// Returns when the current token expires, if the app is authorized and the expiry is known.
+	/// The token is renewed automatically shortly before then if the authorization allows it.
+	pub fn token_expiry(&self) -> Option<Instant> {
+		self.inner.token_expiry()
+	}
+
+	/// Returns the scopes reddit granted the current authorization, if it's known
+	pub fn granted_scopes(&self) -> Option<Scopes> {
+		self.inner.granted_scopes()
+	}
+
+	/// Authorize this app as an installed app with a refresh token saved from an earlier
+	/// authorization, instead of sending the user through the browser again
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `refresh_token` - The refresh token of the earlier authorization
+	pub fn authorize_with_refresh_token(&mut self, id: &str, refresh_token: &str) -> Result<(), Error> {
+		self.block_on(self.inner.authorize_with_refresh_token(id, refresh_token))
+	}
+
+	/// Sets where the authorization is saved whenever this app is authorized or gets a new token.
+	/// `None` stops saving it.
+	/// # Arguments
+	/// * `store` - The token store to save to
+	pub fn set_token_store(&self, store: Option<Arc<dyn TokenStore>>) {
+		self.inner.set_token_store(store);
+	}
+
+	/// Logs out by revoking the access token and refresh token of the current authorization with
+	/// reddit, so they can't be used anymore. The authorization is dropped afterwards, along with
+	/// the one saved in the token store, even if reddit couldn't be reached.
+	pub fn revoke(&self) -> Result<(), Error> {
+		self.block_on(self.inner.revoke())
+	}
+
+	/// Drops the current authorization without telling reddit, along with the one saved in the
+	/// token store. Its tokens stay valid until they expire, use `revoke` to invalidate them.
+	pub fn deauthorize(&self) {
+		self.inner.deauthorize();
+	}
+
+	/// Authorize this app with the authorization saved in the token store. Returns whether there
+	/// was one saved. If its token has expired, a new one is retrieved with the next request.
+	pub fn authorize_from_store(&mut self) -> Result<bool, Error> {
+		self.inner.authorize_from_store()
+	}
+
+	/// Authorize this app without a user, as a confidential client (a script or web app)
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	pub fn authorize_application(&mut self, id: &str, secret: &str) -> Result<(), Error> {
+		self.block_on(self.inner.authorize_application(id, secret))
+	}
+
+	/// Authorize this app without a user, as an installed app
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `device_id` - A unique id of 20-30 characters for the device, or
+	///   `DO_NOT_TRACK_THIS_DEVICE` if the device shouldn't be tracked
+	pub fn authorize_installed_client(&mut self, id: &str, device_id: &str) -> Result<(), Error> {
+		self.block_on(self.inner.authorize_installed_client(id, device_id))
+	}
+
+	/// Starts authorizing this app as an installed app without opening a browser or starting a
+	/// server, for machines without either. Returns the url the user should visit, on any device.
+	/// Once they authorize the app, reddit redirects them to `redirect`, and the url they end up
+	/// at should be passed to `complete_installed_app`.
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `redirect` - The app redirect URI registered on Reddit
+	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
+	/// * `duration` - Whether the authorization should last beyond the first token
+	pub fn begin_installed_app(&self, id: &str, redirect: &str, scopes: &Scopes, duration: TokenDuration) -> String {
+		self.inner.begin_installed_app(id, redirect, scopes, duration)
+	}
+
+	/// Finishes authorizing this app as an installed app started with `begin_installed_app`. The
+	/// state in the url is checked against the one that was sent to reddit before the code is
+	/// exchanged for a token.
+	/// # Arguments
+	/// * `pasted` - The url the user was redirected to. Just the `code` in it is accepted too, but
+	///   then the state can't be checked, so only when there is a single installed app authorization
+	///   in progress.
+	pub fn complete_installed_app(&mut self, pasted: &str) -> Result<(), Error> {
+		self.block_on(self.inner.complete_installed_app(pasted))
+	}
+
+	/// Starts authorizing this app as a web app. Returns the url to send the user to, after which
+	/// reddit redirects them to `redirect` with `code` and `state` query parameters that should be
+	/// passed to `complete_web_app`.
+	/// # Arguments
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `redirect` - The app redirect URI registered on Reddit
+	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
+	pub fn begin_web_app(&self, id: &str, secret: &str, redirect: &str, scopes: &Scopes) -> String {
+		self.inner.begin_web_app(id, secret, redirect, scopes)
+	}
+
+	/// Finishes authorizing this app as a web app by exchanging the code reddit redirected the user
+	/// back with for a token
+	/// # Arguments
+	/// * `code` - The `code` query parameter of the redirect
+	/// * `state` - The `state` query parameter of the redirect
+	pub fn complete_web_app(&mut self, code: &str, state: &str) -> Result<(), Error> {
+		self.block_on(self.inner.complete_web_app(code, state))
+	}
 }
@@ -0,0 +1,223 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::Error;
+
+use app::{App, AsyncApp};
+use net::auth::OAuth;
+use net::proxy::Proxy;
+use net::transport::{HyperConfig, HyperTransport, Transport};
+use net::{Connection, Hosts, LimitMethod};
+
+/// Configures and creates an `App`. Nothing is checked until `build` is called, which returns an
+/// error describing what's wrong instead of panicking.
+///
+/// The user agent is made from the name, version and author of the app as reddit asks, like
+/// `linux:mybot:v1.0 (by /u/IntrepidPig)`, unless a complete one is given with `user_agent`.
+///
+/// ```rust,no_run
+/// # use orca::App;
+/// # use std::time::Duration;
+/// let reddit = App::builder()
+///     .name("mybot")
+///     .version("v1.0")
+///     .author("/u/IntrepidPig")
+///     .connect_timeout(Duration::from_secs(10))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct AppBuilder {
+	name: Option<String>,
+	version: Option<String>,
+	author: Option<String>,
+	platform: Option<String>,
+	user_agent: Option<String>,
+	hyper: HyperConfig,
+	proxy: Option<Option<Proxy>>,
+	custom_hyper: bool,
+	transport: Option<Arc<dyn Transport>>,
+	timeout: Option<Option<Duration>>,
+	hosts: Option<Hosts>,
+	limit: Option<LimitMethod>,
+	auth: Option<OAuth>,
+}
+
+impl AppBuilder {
+	/// Creates a builder with nothing set
+	pub fn new() -> AppBuilder {
+		AppBuilder::default()
+	}
+
+	/// Sets the name of the app, used in the user agent
+	/// # Arguments
+	/// * `name` - Unique app name
+	pub fn name(mut self, name: &str) -> AppBuilder {
+		self.name = Some(name.to_string());
+		self
+	}
+
+	/// Sets the version of the app, used in the user agent
+	/// # Arguments
+	/// * `version` - App version
+	pub fn version(mut self, version: &str) -> AppBuilder {
+		self.version = Some(version.to_string());
+		self
+	}
+
+	/// Sets the author of the app, used in the user agent
+	/// # Arguments
+	/// * `author` - Author of the app (should be in reddit form as /u/<username>)
+	pub fn author(mut self, author: &str) -> AppBuilder {
+		self.author = Some(author.to_string());
+		self
+	}
+
+	/// Sets the platform the app runs on, used in the user agent. Defaults to `linux`.
+	/// # Arguments
+	/// * `platform` - The platform, like `linux`, `windows` or `android`
+	pub fn platform(mut self, platform: &str) -> AppBuilder {
+		self.platform = Some(platform.to_string());
+		self
+	}
+
+	/// Sets the complete user agent, instead of making it from the name, version and author
+	/// # Arguments
+	/// * `user_agent` - The user agent to send with every request
+	pub fn user_agent(mut self, user_agent: &str) -> AppBuilder {
+		self.user_agent = Some(user_agent.to_string());
+		self
+	}
+
+	/// Sets the longest time to wait for the response to a request, including its body. Defaults
+	/// to 60 seconds.
+	/// # Arguments
+	/// * `timeout` - The longest time to wait, or `None` to wait as long as it takes
+	pub fn request_timeout(mut self, timeout: Option<Duration>) -> AppBuilder {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Sets the longest time to wait for a connection to reddit to be established
+	/// # Arguments
+	/// * `timeout` - The longest time to wait
+	pub fn connect_timeout(mut self, timeout: Duration) -> AppBuilder {
+		self.hyper.connect_timeout = Some(timeout);
+		self.custom_hyper = true;
+		self
+	}
+
+	/// Sends requests through an HTTP proxy. By default, the proxy set in the environment is
+	/// used if there is one, see `Proxy::from_env`.
+	/// # Arguments
+	/// * `proxy` - The proxy to send requests through, or `None` to always connect directly
+	pub fn proxy(mut self, proxy: Option<Proxy>) -> AppBuilder {
+		self.proxy = Some(proxy);
+		self.custom_hyper = true;
+		self
+	}
+
+	/// Sets the number of threads resolving host names. Defaults to 4.
+	/// # Arguments
+	/// * `threads` - The number of threads
+	pub fn dns_threads(mut self, threads: usize) -> AppBuilder {
+		self.hyper.dns_threads = threads;
+		self.custom_hyper = true;
+		self
+	}
+
+	/// Sends requests through a custom transport instead of over HTTPS with hyper. The connect
+	/// timeout, proxy and DNS settings only apply to hyper, so they can't be used with this.
+	/// # Arguments
+	/// * `transport` - The transport to send requests with, such as a `MockTransport`
+	pub fn transport(mut self, transport: Arc<dyn Transport>) -> AppBuilder {
+		self.transport = Some(transport);
+		self
+	}
+
+	/// Sets the hosts requests are sent to, for example to target a local stand-in for reddit
+	/// # Arguments
+	/// * `hosts` - The OAuth API, public and token endpoint hosts to use
+	pub fn hosts(mut self, hosts: Hosts) -> AppBuilder {
+		self.hosts = Some(hosts);
+		self
+	}
+
+	/// Sets the method to use for ratelimiting
+	/// # Arguments
+	/// * `limit` - The method to use for ratelimiting
+	pub fn ratelimit(mut self, limit: LimitMethod) -> AppBuilder {
+		self.limit = Some(limit);
+		self
+	}
+
+	/// Starts the app off authorized, for example with an authorization saved earlier
+	/// # Arguments
+	/// * `auth` - The authorization to use
+	pub fn auth(mut self, auth: OAuth) -> AppBuilder {
+		self.auth = Some(auth);
+		self
+	}
+
+	/// Creates the app
+	pub fn build(self) -> Result<App, Error> {
+		self.build_async().map(App::from)
+	}
+
+	/// Creates the asynchronous version of the app
+	pub fn build_async(self) -> Result<AsyncApp, Error> {
+		let user_agent = self.make_user_agent()?;
+
+		let transport = match self.transport {
+			Some(_) if self.custom_hyper => return Err(format_err!("Connect timeouts, proxies and DNS threads can't be set along with a custom transport")),
+			Some(transport) => transport,
+			None => {
+				let mut hyper = self.hyper;
+				hyper.proxy = match self.proxy {
+					Some(proxy) => proxy,
+					None => Proxy::from_env()?,
+				};
+				Arc::new(HyperTransport::with_config(hyper)?)
+			}
+		};
+
+		let conn = Connection::with_useragent(&user_agent, transport).map_err(|e| format_err!("User agent {:?} is invalid: {}", user_agent, e))?;
+		if let Some(timeout) = self.timeout {
+			conn.set_timeout(timeout);
+		}
+		if let Some(hosts) = self.hosts {
+			conn.set_hosts(hosts);
+		}
+		if let Some(limit) = self.limit {
+			conn.set_limit(limit);
+		}
+		if let Some(auth) = self.auth {
+			conn.set_auth(auth);
+		}
+
+		Ok(AsyncApp { conn: Arc::new(conn), timeout: None })
+	}
+
+	fn make_user_agent(&self) -> Result<String, Error> {
+		if let Some(ref user_agent) = self.user_agent {
+			if user_agent.trim().is_empty() {
+				return Err(format_err!("User agent is empty"));
+			}
+			return Ok(user_agent.clone());
+		}
+
+		let mut parts = Vec::new();
+		for &(part, value) in &[("name", &self.name), ("version", &self.version), ("author", &self.author)] {
+			match *value {
+				Some(ref value) if !value.trim().is_empty() => parts.push(value.as_str()),
+				_ => return Err(format_err!("The app {} must be set to make a user agent, or a complete user agent given", part)),
+			}
+		}
+		let platform = match self.platform {
+			Some(ref platform) => platform,
+			None => "linux",
+		};
+
+		Ok(format!("{}:{}:{} (by {})", platform, parts[0], parts[1], parts[2]))
+	}
+}
@@ -1,29 +1,23 @@
-use std::collections::{HashMap, VecDeque};
-
 use failure::Error;
+use futures::{future, stream, Future, Stream};
 use hyper::Request;
 use json::Value;
-use url::form_urlencoded;
 
-use data::{Comment, Listing};
-use net::body_from_map;
-use {App, RedditError};
+use data::{Comment, Listing, MoreComments};
+use net::Form;
+use {App, AsyncApp, RedditError, RedditFuture};
 
-impl App {
+impl AsyncApp {
 	/// Comment on a thing. The `thing` can be a post, a comment, or a private message
 	/// # Arguments
 	/// * `text` - The body of the comment
 	/// * `thing` - Fullname of the thing to comment on
-	pub fn comment(&self, text: &str, thing: &str) -> Result<(), Error> {
-		let text: String = form_urlencoded::byte_serialize(text.as_bytes()).collect();
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("text", &text);
-		params.insert("thing_id", thing);
+	pub fn comment(&self, text: &str, thing: &str) -> RedditFuture<()> {
+		let form = Form::new().param("text", text).param("thing_id", thing).param("api_type", "json");
 
-		let req = Request::post("https://oauth.reddit.com/api/comment").body(body_from_map(&params)).unwrap();
+		let req = Request::post(self.conn.oauth_url("/api/comment")).body(form.into()).unwrap();
 
-		self.conn.run_auth_request(req)?;
-		Ok(())
+		Box::new(self.send_write(req, "submit").map(|_| ()))
 	}
 
 	/// Load more comments from a comment tree that is not completely loaded. This function at the moment can only be called
@@ -32,14 +26,8 @@ impl App {
 	/// * `link_id` - The id of the post that has the comments that are being loaded
 	/// * `morechildren_id` - The id of the morechildren object that is being loaded
 	/// * `comments` - Slice of `&str`s that are the ids of the comments to be loaded
-	pub fn more_children(&self, link_id: &str, morechildren_id: &str, comments: &[&str]) -> Result<Listing<Comment>, Error> {
-		let mut string = String::from("t3_");
-		let link_id = if !link_id.starts_with("t3_") {
-			string.push_str(link_id);
-			&string
-		} else {
-			link_id
-		};
+	pub fn more_children(&self, link_id: &str, morechildren_id: &str, comments: &[&str]) -> RedditFuture<Listing<Comment>> {
+		let link_id = if !link_id.starts_with("t3_") { format!("t3_{}", link_id) } else { link_id.to_string() };
 
 		let limit = 5;
 		// Break requests into chunks of `limit`
@@ -59,39 +47,54 @@ impl App {
 
 		trace!("Chunks are {:?}", chunks);
 
-		let mut lists = Vec::new();
-
-		for chunk in chunks {
-			let mut params: HashMap<&str, &str> = HashMap::new();
-			params.insert("children", &chunk);
-			params.insert("link_id", link_id);
-			params.insert("id", morechildren_id);
-			params.insert("api_type", "json");
-
-			trace!("Getting more children {} from {}", chunk, link_id);
-
-			//let mut req = Request::new(Method::Get, Url::parse_with_params("https://www.reddit.com/api/morechildren/.json", params)?.into_string().parse()?);
-			let req = Request::post("https://www.reddit.com/api/morechildren/.json").body(body_from_map(&params)).unwrap();
-			let data = self.conn.run_request(req)?;
-
-			trace!("Scanning {}", data);
-
-			let list: Listing<Comment> = Listing::from_value(&data["json"]["data"]["things"], link_id, self)?;
-			lists.push(list);
-		}
-
-		// Flatten the vec of listings
-		let mut final_list = VecDeque::new();
-		for list in &mut lists {
-			final_list.append(&mut list.children);
-		}
-		let mut listing: Listing<Comment> = Listing::new();
+		let app = self.clone();
+		let morechildren_id = morechildren_id.to_string();
+		let chunk_link_id = link_id.clone();
+		// Send the requests one at a time
+		let lists = stream::iter_ok(chunks)
+			.and_then(move |chunk| {
+				trace!("Getting more children {} from {}", chunk, chunk_link_id);
+
+				let form = Form::new().param("children", chunk).param("link_id", chunk_link_id.as_str()).param("id", morechildren_id.as_str()).param("api_type", "json");
+
+				let req = Request::post(app.conn.read_url("/api/morechildren/.json")).body(form.into()).unwrap();
+				app.send_read(req)
+			})
+			.collect();
+
+		let app = self.clone();
+		Box::new(lists.and_then(move |lists| {
+			let mut mores = Vec::new();
+			let mut listing: Listing<Comment> = Listing::new();
+
+			for data in lists {
+				trace!("Scanning {}", data);
+
+				// Flatten the listings
+				for comment in Listing::parse(&data["json"]["data"]["things"], &mut mores)? {
+					listing.insert_comment(comment);
+				}
+			}
 
-		for comment in final_list {
-			listing.insert_comment(comment);
-		}
+			Ok(app.load_more(&link_id, listing, mores))
+		}).flatten())
+	}
 
-		Ok(listing)
+	/// Loads the comments that were left out of a listing and inserts them in their places in the tree
+	pub(crate) fn load_more(&self, post_id: &str, listing: Listing<Comment>, mores: Vec<MoreComments>) -> RedditFuture<Listing<Comment>> {
+		let app = self.clone();
+		let post_id = post_id.to_string();
+
+		Box::new(stream::iter_ok(mores).fold(listing, move |mut listing, more| {
+			let children = more.children.iter().map(|c| c.as_str()).collect::<Vec<&str>>();
+			app.more_children(&post_id, &more.id, &children).map(move |loaded| {
+				for comment in loaded {
+					listing.insert_comment(comment);
+				}
+				trace!("Successfully got children");
+				listing
+			})
+		}))
 	}
 
 	/// Sticky a post in a subreddit. Does nothing if the post is already stickied
@@ -99,29 +102,75 @@ impl App {
 	/// * `sticky` - boolean value. True to set post as sticky, false to unset post as sticky
 	/// * `slot` - Optional slot number to fill (can only be 1 or 2, and will error otherwise)
 	/// * `id` - _fullname_ of the post to sticky
-	pub fn set_sticky(&self, sticky: bool, slot: Option<i32>, id: &str) -> Result<(), Error> {
-		let numstr;
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("state", if sticky { "1" } else { "0" });
+	pub fn set_sticky(&self, sticky: bool, slot: Option<i32>, id: &str) -> RedditFuture<()> {
+		let mut form = Form::new().param("state", if sticky { "1" } else { "0" });
 
 		if let Some(num) = slot {
 			if num != 1 && num != 2 {
-				return Err(Error::from(RedditError::BadRequest {
+				return Box::new(future::err(Error::from(RedditError::BadRequest {
 					request: "Sticky's are limited to slots 1 and 2".to_string(),
 					response: "not sent".to_string(),
-				}));
+				})));
 			}
-			numstr = num.to_string();
-			params.insert("num", &numstr);
+			form = form.param("num", num.to_string());
 		}
 
-		params.insert("id", id);
+		let form = form.param("id", id);
+
+		let req = Request::post(self.conn.oauth_url("/api/set_subreddit_sticky/.json")).body(form.into()).unwrap();
+
+		Box::new(self.send_auth(req, "modposts").then(|_| Ok(())))
+	}
+
+	/// Submit a self post
+	/// # Arguments
+	/// * `sub` - Name of the subreddit to submit a post to
+	/// * `title` - Title of the post
+	/// * `text` - Body of the post
+	/// * `sendreplies` - Whether replies should be forwarded to the inbox of the submitter
+	/// # Returns
+	/// A future of reddit's json response to the submission
+	pub fn submit_self(&self, sub: &str, title: &str, text: &str, sendreplies: bool) -> RedditFuture<Value> {
+		let form = Form::new()
+			.param("sr", sub)
+			.param("kind", "self")
+			.param("title", title)
+			.param("text", text)
+			.param("sendreplies", if sendreplies { "true" } else { "false" })
+			.param("api_type", "json");
+
+		let req = Request::post(self.conn.oauth_url("/api/submit/.json")).body(form.into()).unwrap();
+
+		self.send_write(req, "submit")
+	}
+}
 
-		let req = Request::post("https://oauth.reddit.com/api/set_subreddit_sticky/.json").body(body_from_map(&params)).unwrap();
+impl App {
+	/// Comment on a thing. The `thing` can be a post, a comment, or a private message
+	/// # Arguments
+	/// * `text` - The body of the comment
+	/// * `thing` - Fullname of the thing to comment on
+	pub fn comment(&self, text: &str, thing: &str) -> Result<(), Error> {
+		self.block_on(self.inner.comment(text, thing))
+	}
 
-		self.conn.run_auth_request(req).ok();
+	/// Load more comments from a comment tree that is not completely loaded. This function at the moment can only be called
+	/// internally due to requiring `morechildren_id` that is not available in the `Thread` type.
+	/// # Arguments
+	/// * `link_id` - The id of the post that has the comments that are being loaded
+	/// * `morechildren_id` - The id of the morechildren object that is being loaded
+	/// * `comments` - Slice of `&str`s that are the ids of the comments to be loaded
+	pub fn more_children(&self, link_id: &str, morechildren_id: &str, comments: &[&str]) -> Result<Listing<Comment>, Error> {
+		self.block_on(self.inner.more_children(link_id, morechildren_id, comments))
+	}
 
-		Ok(())
+	/// Sticky a post in a subreddit. Does nothing if the post is already stickied
+	/// # Arguments
+	/// * `sticky` - boolean value. True to set post as sticky, false to unset post as sticky
+	/// * `slot` - Optional slot number to fill (can only be 1 or 2, and will error otherwise)
+	/// * `id` - _fullname_ of the post to sticky
+	pub fn set_sticky(&self, sticky: bool, slot: Option<i32>, id: &str) -> Result<(), Error> {
+		self.block_on(self.inner.set_sticky(sticky, slot, id))
 	}
 
 	/// Submit a self post
@@ -133,17 +182,6 @@ impl App {
 	/// # Returns
 	/// A result with reddit's json response to the submission
 	pub fn submit_self(&self, sub: &str, title: &str, text: &str, sendreplies: bool) -> Result<Value, Error> {
-		let title: String = form_urlencoded::byte_serialize(title.as_bytes()).collect();
-		let text: String = form_urlencoded::byte_serialize(text.as_bytes()).collect();
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("sr", sub);
-		params.insert("kind", "self");
-		params.insert("title", &title);
-		params.insert("text", &text);
-		params.insert("sendreplies", if sendreplies { "true" } else { "false" });
-
-		let req = Request::post("https://oauth.reddit.com/api/submit/.json").body(body_from_map(&params)).unwrap();
-
-		self.conn.run_auth_request(req)
+		self.block_on(self.inner.submit_self(sub, title, text, sendreplies))
 	}
 }
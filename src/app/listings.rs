@@ -1,26 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use failure::Error;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
 use hyper::{Body, Request};
-use json::Value;
 use url::Url;
 
-use data::{Comment, Comments, Listing, Post, Thing};
-use net::{body_from_map, uri_params_from_map};
-use {App, Sort};
+use data::{Comment, Comments, Listing, Post};
+use net::{uri_params_from_map, Form};
+use {App, AsyncApp, RedditFuture, Sort};
 
-impl App {
+/// The comments loaded by a comment stream but not returned yet, and the newest comment loaded
+type StreamState = (VecDeque<Comment>, Option<String>);
+
+impl AsyncApp {
 	/// Loads a thing and casts it to the type of anything as long as it implements the Thing trait. Experimental
 	/// # Arguments
 	/// * `fullame` - fullname of the thing
-	pub fn load_post(&self, fullname: &str) -> Result<Post, Error> {
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("names", fullname);
-
-		let req = Request::get(format!("https://www.reddit.com/by_id/{}/.json", fullname)).body(Body::empty()).unwrap();
-		let response = self.conn.run_request(req)?;
-
-		Post::from_value(&response, self)
+	pub fn load_post(&self, fullname: &str) -> RedditFuture<Post> {
+		let req = Request::get(self.conn.read_url(&format!("/by_id/{}/.json", fullname))).body(Body::empty()).unwrap();
+
+		let app = self.clone();
+		Box::new(self.send_read(req).and_then(move |response| {
+			let id = match Post::parse_id(&response) {
+				Ok(id) => id,
+				Err(e) => return future::Either::A(future::err(e)),
+			};
+			future::Either::B(app.get_comment_tree(&id).and_then(move |comments| Post::parse(&response, comments)))
+		}))
 	}
 
 	/// Get the posts in a subreddit sorted in a specific way
@@ -28,30 +35,42 @@ impl App {
 	/// * `sub` - Name of subreddit to query
 	/// * `sort` - Sort method of query
 	/// # Returns
-	/// A result containing a json listing of posts
-	pub fn get_posts(&self, sub: &str, sort: Sort) -> Result<Value, Error> {
-		let req = Request::get(
-			Url::parse_with_params(
-				&format!(
-					"https://www.reddit.com/r/{}/.\
-					 json",
-					sub
-				),
-				sort.param(),
-			)?
-			.into_string(),
-		)
-		.body(Body::empty())
-		.unwrap();
-
-		self.conn.run_request(req)
+	/// A future of a listing of posts, without the comments on them
+	pub fn get_posts(&self, sub: &str, sort: Sort) -> RedditFuture<Listing<Post>> {
+		let url = match Url::parse_with_params(&self.conn.read_url(&format!("/r/{}/.json", sub)), sort.param()) {
+			Ok(url) => url,
+			Err(e) => return Box::new(future::err(Error::from(e))),
+		};
+		let req = Request::get(url.into_string()).body(Body::empty()).unwrap();
+
+		Box::new(self.send_read(req).and_then(|response| Listing::parse_posts(&response["data"]["children"])))
 	}
 
-	/// Get a iterator of all comments in order of being posted
+	/// Get a stream of all comments in order of being posted
 	/// # Arguments
 	/// * `sub` - Name of the subreddit to pull comments from. Can be 'all' to pull from all of reddit
-	pub fn create_comment_stream(&self, sub: &str) -> Comments {
-		Comments::new(self, sub)
+	pub fn create_comment_stream(&self, sub: &str) -> Box<dyn Stream<Item = Comment, Error = Error> + Send> {
+		let app = self.clone();
+		let sub = sub.to_string();
+
+		Box::new(stream::unfold((VecDeque::new(), None), move |(cache, last): StreamState| {
+			let app = app.clone();
+			let sub = sub.clone();
+			// Keep loading recent comments until there's one to return
+			let next = future::loop_fn((cache, last), move |(mut cache, last)| -> RedditFuture<Loop<(Comment, StreamState), StreamState>> {
+				if let Some(comment) = cache.pop_front() {
+					return Box::new(future::ok(Loop::Break((comment, (cache, last)))));
+				}
+
+				Box::new(app.get_recent_comments(&sub, Some(500), last.as_deref()).map(move |mut resp| {
+					let last = resp.children.front().map(|comment| comment.name.clone()).or(last);
+					cache.append(&mut resp.children);
+					Loop::Continue((cache, last))
+				}))
+			});
+
+			Some(next)
+		}))
 	}
 
 	/// Gets the most recent comments in a subreddit. This function is also usually called internally but
@@ -61,8 +80,8 @@ impl App {
 	/// * `limit` - Optional limit to amount of comments loaded
 	/// * `before` - Optional comment to be the starting point for the next comments loaded
 	/// # Returns
-	/// A listing of comments that should be flat (no replies)
-	pub fn get_recent_comments(&self, sub: &str, limit: Option<i32>, before: Option<&str>) -> Result<Listing<Comment>, Error> {
+	/// A future of a listing of comments that should be flat (no replies)
+	pub fn get_recent_comments(&self, sub: &str, limit: Option<i32>, before: Option<&str>) -> RedditFuture<Listing<Comment>> {
 		let limit_str;
 		let mut params: HashMap<&str, &str> = HashMap::new();
 		if let Some(limit) = limit {
@@ -73,12 +92,22 @@ impl App {
 			params.insert("before", before);
 		}
 
-		let req = Request::get(uri_params_from_map(&format!("https://www.reddit.com/r/{}/comments.json", sub), &params)?).body(Body::empty()).unwrap();
-
-		let resp = self.conn.run_request(req)?;
-		let comments = Listing::from_value(&resp["data"]["children"], "", self)?;
-
-		Ok(comments)
+		let uri = match uri_params_from_map(&self.conn.read_url(&format!("/r/{}/comments.json", sub)), &params) {
+			Ok(uri) => uri,
+			Err(e) => return Box::new(future::err(e)),
+		};
+		let req = Request::get(uri).body(Body::empty()).unwrap();
+
+		let app = self.clone();
+		Box::new(
+			self.send_read(req)
+				.and_then(|resp| {
+					let mut mores = Vec::new();
+					let comments = Listing::parse(&resp["data"]["children"], &mut mores)?;
+					Ok((comments, mores))
+				})
+				.and_then(move |(comments, mores)| app.load_more("", comments, mores)),
+		)
 	}
 
 	/// Loads the comment tree of a post, returning a listing of the Comment enum, which can be
@@ -86,18 +115,71 @@ impl App {
 	/// # Arguments
 	/// * `post` - The name of the post to retrieve the tree from
 	/// # Returns
-	/// A fully populated listing of commments (no `more` values)
-	pub fn get_comment_tree(&self, post: &str) -> Result<Listing<Comment>, Error> {
+	/// A future of a fully populated listing of commments (no `more` values)
+	pub fn get_comment_tree(&self, post: &str) -> RedditFuture<Listing<Comment>> {
 		// TODO add sorting and shit
 
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("limit", "2147483648");
-		params.insert("depth", "2147483648");
-		let req = Request::get(format!("https://www.reddit.com/comments/{}/.json", post)).body(body_from_map(&params)).unwrap();
+		let form = Form::new().param("limit", "2147483648").param("depth", "2147483648");
+		let req = Request::get(self.conn.read_url(&format!("/comments/{}/.json", post))).body(form.into()).unwrap();
+
+		let app = self.clone();
+		let post = post.to_string();
+		Box::new(
+			self.send_read(req)
+				.and_then(|data| {
+					let mut mores = Vec::new();
+					let comments = Listing::parse(&data[1]["data"]["children"], &mut mores)?;
+					Ok((comments, mores))
+				})
+				.and_then(move |(comments, mores)| app.load_more(&post, comments, mores)),
+		)
+	}
+}
+
+impl App {
+	/// Loads a thing and casts it to the type of anything as long as it implements the Thing trait. Experimental
+	/// # Arguments
+	/// * `fullame` - fullname of the thing
+	pub fn load_post(&self, fullname: &str) -> Result<Post, Error> {
+		self.block_on(self.inner.load_post(fullname))
+	}
+
+	/// Get the posts in a subreddit sorted in a specific way
+	/// # Arguments
+	/// * `sub` - Name of subreddit to query
+	/// * `sort` - Sort method of query
+	/// # Returns
+	/// A result containing a listing of posts, without the comments on them
+	pub fn get_posts(&self, sub: &str, sort: Sort) -> Result<Listing<Post>, Error> {
+		self.block_on(self.inner.get_posts(sub, sort))
+	}
 
-		let data = self.conn.run_request(req)?;
-		let data = data[1]["data"]["children"].clone();
+	/// Get a iterator of all comments in order of being posted
+	/// # Arguments
+	/// * `sub` - Name of the subreddit to pull comments from. Can be 'all' to pull from all of reddit
+	pub fn create_comment_stream(&self, sub: &str) -> Comments {
+		Comments::new(self, sub)
+	}
+
+	/// Gets the most recent comments in a subreddit. This function is also usually called internally but
+	/// can be called if a one time retrieval of recent comments from a subreddit is necessary
+	/// # Arguments
+	/// * `sub` - Subreddit to load recent comments from
+	/// * `limit` - Optional limit to amount of comments loaded
+	/// * `before` - Optional comment to be the starting point for the next comments loaded
+	/// # Returns
+	/// A listing of comments that should be flat (no replies)
+	pub fn get_recent_comments(&self, sub: &str, limit: Option<i32>, before: Option<&str>) -> Result<Listing<Comment>, Error> {
+		self.block_on(self.inner.get_recent_comments(sub, limit, before))
+	}
 
-		Listing::from_value(&data, post, self)
+	/// Loads the comment tree of a post, returning a listing of the Comment enum, which can be
+	/// either Loaded or NotLoaded
+	/// # Arguments
+	/// * `post` - The name of the post to retrieve the tree from
+	/// # Returns
+	/// A fully populated listing of commments (no `more` values)
+	pub fn get_comment_tree(&self, post: &str) -> Result<Listing<Comment>, Error> {
+		self.block_on(self.inner.get_comment_tree(post))
 	}
 }
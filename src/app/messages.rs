@@ -1,31 +1,32 @@
-use std::collections::HashMap;
-
 use failure::Error;
+use futures::Future;
 use hyper::Request;
-use url::form_urlencoded;
 
-use net::body_from_map;
-use App;
+use net::Form;
+use {App, AsyncApp, RedditFuture};
 
-impl App {
+impl AsyncApp {
 	/// Send a private message to a user
 	/// # Arguments
 	/// * `to` - Name of the user to send a message to
 	/// * `subject` - Subject of the message
 	/// * `body` - Body of the message
-	pub fn message(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
-		let subject: String = form_urlencoded::byte_serialize(subject.as_bytes()).collect();
-		let body: String = form_urlencoded::byte_serialize(body.as_bytes()).collect();
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("to", to);
-		params.insert("subject", &subject);
-		params.insert("text", &body);
+	pub fn message(&self, to: &str, subject: &str, body: &str) -> RedditFuture<()> {
+		let form = Form::new().param("to", to).param("subject", subject).param("text", body).param("api_type", "json");
 
-		let req = Request::post("https://oauth.reddit.com/api/compose/.json").body(body_from_map(&params)).unwrap();
+		let req = Request::post(self.conn.oauth_url("/api/compose/.json")).body(form.into()).unwrap();
 
-		match self.conn.run_auth_request(req) {
-			Ok(_) => Ok(()),
-			Err(e) => Err(e),
-		}
+		Box::new(self.send_write(req, "privatemessages").map(|_| ()))
+	}
+}
+
+impl App {
+	/// Send a private message to a user
+	/// # Arguments
+	/// * `to` - Name of the user to send a message to
+	/// * `subject` - Subject of the message
+	/// * `body` - Body of the message
+	pub fn message(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+		self.block_on(self.inner.message(to, subject, body))
 	}
 }
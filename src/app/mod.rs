@@ -1,19 +1,39 @@
 mod account;
 mod auth;
+mod builder;
 mod links;
 mod listings;
 mod messages;
 mod users;
 
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use failure::Error;
+use futures::future::{self, Loop};
+use futures::Future;
+use hyper::{Body, Request};
+use json::Value;
+use tokio_timer::Delay;
+
+pub use self::builder::AppBuilder;
 
-use net::{Connection, LimitMethod};
+use errors::{ApiErrorCode, RedditError};
+use net::ratelimit::{Budget, RateLimiter};
+use net::transport::Transport;
+use net::{BufferedRequest, Connection, Hosts, LimitMethod, RequestTimeout, RetryPolicy};
+
+/// A future returned by the methods of `AsyncApp`
+pub type RedditFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
 
 /// A reddit object
 /// ## Usage:
 /// To create a new instance, use `Reddit::new()`
+///
+/// An app can be shared between threads, for example in an `Arc`. All of them then use the same
+/// authorization and ratelimit.
 pub struct App {
-	pub(crate) conn: Connection,
+	inner: AsyncApp,
 }
 
 impl App {
@@ -25,13 +45,304 @@ impl App {
 	/// # Returns
 	/// A new reddit object
 	pub fn new(appname: &str, appversion: &str, appauthor: &str) -> Result<App, Error> {
-		Ok(App { conn: Connection::new(appname, appversion, appauthor)? })
+		Ok(App { inner: AsyncApp::new(appname, appversion, appauthor)? })
+	}
+
+	/// Returns a builder to configure the user agent, timeouts, proxy and more of a new reddit
+	/// instance
+	pub fn builder() -> AppBuilder {
+		AppBuilder::new()
+	}
+
+	/// Create a new reddit instance that sends its requests through a custom transport. This is
+	/// mostly useful for testing with a `MockTransport`.
+	/// # Arguments
+	/// * `appname` - Unique app name
+	/// * `appversion` - App version
+	/// * `appauthor` - Auther of the app
+	/// * `transport` - The transport to send requests with
+	/// # Returns
+	/// A new reddit object
+	pub fn with_transport(appname: &str, appversion: &str, appauthor: &str, transport: Arc<dyn Transport>) -> Result<App, Error> {
+		Ok(App {
+			inner: AsyncApp::with_transport(appname, appversion, appauthor, transport)?,
+		})
+	}
+
+	/// Sets the hosts requests are sent to, for example to target a local stand-in for reddit
+	/// # Arguments
+	/// * `hosts` - The OAuth API, public and token endpoint hosts to use
+	pub fn set_hosts(&self, hosts: Hosts) {
+		self.inner.set_hosts(hosts);
 	}
 
 	/// Sets the method to use for ratelimiting.
+	///
+	/// **The method belongs to the ratelimiter**, so if it's shared with other apps through
+	/// `set_ratelimiter`, this changes the method for all of them.
+	/// # Arguments
+	/// * `limit` - The method to use for ratelimiting
+	pub fn set_ratelimiting(&self, limit: LimitMethod) {
+		self.inner.set_ratelimiting(limit);
+	}
+
+	/// Replaces the ratelimiter. Apps using the same OAuth client share reddit's ratelimit, so
+	/// they should share a ratelimiter too. The ratelimiting method is shared along with it, so
+	/// `set_ratelimiting` on any of the apps changes it for all of them.
+	/// # Arguments
+	/// * `ratelimiter` - The ratelimiter to use, such as one returned by `ratelimiter` of another app
+	pub fn set_ratelimiter(&self, ratelimiter: Arc<dyn RateLimiter>) {
+		self.inner.set_ratelimiter(ratelimiter);
+	}
+
+	/// Returns the ratelimiter of this app
+	pub fn ratelimiter(&self) -> Arc<dyn RateLimiter> {
+		self.inner.ratelimiter()
+	}
+
+	/// Returns how many requests were used and are remaining in the current ratelimit period,
+	/// and when it resets
+	pub fn ratelimit_budget(&self) -> Budget {
+		self.inner.ratelimit_budget()
+	}
+
+	/// Sets how requests that failed for temporary reasons are retried. By default, requests
+	/// that only read from reddit are retried up to 3 times.
+	/// # Arguments
+	/// * `policy` - The retry policy to use
+	pub fn set_retry_policy(&self, policy: RetryPolicy) {
+		self.inner.set_retry_policy(policy);
+	}
+
+	/// Makes `comment`, `submit_self` and `message` wait out reddit's per-account write ratelimit
+	/// ("you are doing that too much. try again in 5 minutes.") and try again, instead of
+	/// returning the error. This is off by default.
+	/// # Arguments
+	/// * `max_wait` - The longest total time to wait for a single write, or `None` to turn
+	///   waiting off
+	pub fn set_write_ratelimit_wait(&self, max_wait: Option<Duration>) {
+		self.inner.set_write_ratelimit_wait(max_wait);
+	}
+
+	/// Sets how long requests wait for a response, including its body, before giving up with
+	/// `RedditError::Timeout`. Defaults to 60 seconds.
+	/// # Arguments
+	/// * `timeout` - The longest time to wait, or `None` to wait as long as it takes
+	pub fn set_timeout(&self, timeout: Option<Duration>) {
+		self.inner.set_timeout(timeout);
+	}
+
+	/// Returns a copy of this app, sharing the same connection, whose requests wait for a
+	/// different time than the connection's timeout. Useful for a single slow call, like
+	/// `reddit.with_timeout(None).get_user(name)`.
+	/// # Arguments
+	/// * `timeout` - The longest time to wait, or `None` to wait as long as it takes
+	pub fn with_timeout(&self, timeout: Option<Duration>) -> App {
+		App { inner: self.inner.with_timeout(timeout) }
+	}
+
+	/// Returns the asynchronous version of this app, which shares the same connection
+	pub fn as_async(&self) -> &AsyncApp {
+		&self.inner
+	}
+
+	/// Converts this app into its asynchronous version
+	pub fn into_async(self) -> AsyncApp {
+		self.inner
+	}
+
+	/// Returns the connection this app sends its requests with
+	pub(crate) fn conn(&self) -> &Connection {
+		&self.inner.conn
+	}
+
+	/// Runs a future from the asynchronous api to completion, blocking the current thread
+	fn block_on<F: Future<Error = Error>>(&self, future: F) -> Result<F::Item, Error> {
+		self.inner.conn.run(future)
+	}
+}
+
+impl From<AsyncApp> for App {
+	fn from(inner: AsyncApp) -> App {
+		App { inner }
+	}
+}
+
+/// An asynchronous reddit object. It has the same methods as `App`, but they return futures
+/// instead of blocking, and ratelimiting waits on a timer instead of sleeping the thread. The
+/// futures must be run on a tokio runtime.
+///
+/// Cloning an `AsyncApp` is cheap, and the clones share the same connection.
+#[derive(Clone)]
+pub struct AsyncApp {
+	pub(crate) conn: Arc<Connection>,
+	/// Overrides the timeout of the connection for requests sent by this app
+	timeout: Option<RequestTimeout>,
+}
+
+impl AsyncApp {
+	/// Create a new asynchronous reddit instance
+	/// # Arguments
+	/// * `appname` - Unique app name
+	/// * `appversion` - App version
+	/// * `appauthor` - Auther of the app
+	pub fn new(appname: &str, appversion: &str, appauthor: &str) -> Result<AsyncApp, Error> {
+		Ok(AsyncApp {
+			conn: Arc::new(Connection::new(appname, appversion, appauthor)?),
+			timeout: None,
+		})
+	}
+
+	/// Create a new asynchronous reddit instance that sends its requests through a custom transport
+	/// # Arguments
+	/// * `appname` - Unique app name
+	/// * `appversion` - App version
+	/// * `appauthor` - Auther of the app
+	/// * `transport` - The transport to send requests with
+	pub fn with_transport(appname: &str, appversion: &str, appauthor: &str, transport: Arc<dyn Transport>) -> Result<AsyncApp, Error> {
+		Ok(AsyncApp {
+			conn: Arc::new(Connection::with_transport(appname, appversion, appauthor, transport)?),
+			timeout: None,
+		})
+	}
+
+	/// Sets the hosts requests are sent to, for example to target a local stand-in for reddit
+	/// # Arguments
+	/// * `hosts` - The OAuth API, public and token endpoint hosts to use
+	pub fn set_hosts(&self, hosts: Hosts) {
+		self.conn.set_hosts(hosts);
+	}
+
+	/// Sets the method to use for ratelimiting.
+	///
+	/// **The method belongs to the ratelimiter**, so if it's shared with other apps through
+	/// `set_ratelimiter`, this changes the method for all of them.
 	/// # Arguments
 	/// * `limit` - The method to use for ratelimiting
 	pub fn set_ratelimiting(&self, limit: LimitMethod) {
 		self.conn.set_limit(limit);
 	}
+
+	/// Replaces the ratelimiter. Apps using the same OAuth client share reddit's ratelimit, so
+	/// they should share a ratelimiter too. The ratelimiting method is shared along with it, so
+	/// `set_ratelimiting` on any of the apps changes it for all of them.
+	/// # Arguments
+	/// * `ratelimiter` - The ratelimiter to use, such as one returned by `ratelimiter` of another app
+	pub fn set_ratelimiter(&self, ratelimiter: Arc<dyn RateLimiter>) {
+		self.conn.set_ratelimiter(ratelimiter);
+	}
+
+	/// Returns the ratelimiter of this app
+	pub fn ratelimiter(&self) -> Arc<dyn RateLimiter> {
+		self.conn.ratelimiter()
+	}
+
+	/// Returns how many requests were used and are remaining in the current ratelimit period,
+	/// and when it resets
+	pub fn ratelimit_budget(&self) -> Budget {
+		self.conn.ratelimit_budget()
+	}
+
+	/// Sets how requests that failed for temporary reasons are retried. By default, requests
+	/// that only read from reddit are retried up to 3 times.
+	/// # Arguments
+	/// * `policy` - The retry policy to use
+	pub fn set_retry_policy(&self, policy: RetryPolicy) {
+		self.conn.set_retry_policy(policy);
+	}
+
+	/// Makes `comment`, `submit_self` and `message` wait out reddit's per-account write ratelimit
+	/// ("you are doing that too much. try again in 5 minutes.") and try again, instead of
+	/// returning the error. This is off by default.
+	/// # Arguments
+	/// * `max_wait` - The longest total time to wait for a single write, or `None` to turn
+	///   waiting off
+	pub fn set_write_ratelimit_wait(&self, max_wait: Option<Duration>) {
+		self.conn.set_write_ratelimit_wait(max_wait);
+	}
+
+	/// Sets how long requests wait for a response, including its body, before giving up with
+	/// `RedditError::Timeout`. This applies to every app sharing the connection. Defaults to 60
+	/// seconds.
+	/// # Arguments
+	/// * `timeout` - The longest time to wait, or `None` to wait as long as it takes
+	pub fn set_timeout(&self, timeout: Option<Duration>) {
+		self.conn.set_timeout(timeout);
+	}
+
+	/// Returns a copy of this app, sharing the same connection, whose requests wait for a
+	/// different time than the connection's timeout. Useful for a single slow call, like
+	/// `reddit.with_timeout(None).get_user(name)`.
+	/// # Arguments
+	/// * `timeout` - The longest time to wait, or `None` to wait as long as it takes
+	pub fn with_timeout(&self, timeout: Option<Duration>) -> AsyncApp {
+		AsyncApp {
+			conn: Arc::clone(&self.conn),
+			timeout: Some(RequestTimeout(timeout)),
+		}
+	}
+
+	/// Sends a request that doesn't need authorization
+	fn send(&self, req: Request<Body>) -> RedditFuture<Value> {
+		Connection::send_request(Arc::clone(&self.conn), self.with_request_timeout(req))
+	}
+
+	/// Sends a request with authorization headers, unless the authorization is known to lack the
+	/// scope the request needs
+	fn send_auth(&self, req: Request<Body>, scope: &str) -> RedditFuture<Value> {
+		if let Err(e) = self.conn.require_scope(scope) {
+			return Box::new(future::err(e));
+		}
+		Connection::send_auth_request(Arc::clone(&self.conn), self.with_request_timeout(req))
+	}
+
+	/// Marks a request with the timeout of this app, if it overrides the connection's
+	fn with_request_timeout(&self, mut req: Request<Body>) -> Request<Body> {
+		if let Some(timeout) = self.timeout {
+			req.extensions_mut().insert(timeout);
+		}
+		req
+	}
+
+	/// Sends a request to a url created by `Connection::read_url`, with authorization headers if
+	/// reads are authorized
+	fn send_read(&self, req: Request<Body>) -> RedditFuture<Value> {
+		if self.conn.reads_authorized() {
+			self.send_auth(req, "read")
+		} else {
+			self.send(req)
+		}
+	}
+
+	/// Sends a request with authorization headers that writes something subject to reddit's
+	/// per-account ratelimit, waiting out the ratelimit if that's turned on
+	fn send_write(&self, req: Request<Body>, scope: &'static str) -> RedditFuture<Value> {
+		let max_wait = match self.conn.write_ratelimit_wait() {
+			Some(max_wait) => max_wait,
+			None => return self.send_auth(req, scope),
+		};
+
+		let app = self.clone();
+		Box::new(BufferedRequest::buffer(req).and_then(move |req| {
+			future::loop_fn(Duration::new(0, 0), move |waited| {
+				app.send_auth(req.to_request(), scope).then(move |result| -> RedditFuture<Loop<Value, Duration>> {
+					let wait = match result {
+						Err(ref e) => match e.downcast_ref::<RedditError>() {
+							Some(e @ &RedditError::Api { code: ApiErrorCode::Ratelimit, .. }) => e.ratelimit_wait(),
+							_ => None,
+						},
+						Ok(_) => None,
+					};
+
+					match wait {
+						Some(wait) if waited + wait <= max_wait => {
+							warn!("Hit the write ratelimit, trying again in {:?}", wait);
+							Box::new(Delay::new(Instant::now() + wait).map_err(Error::from).map(move |_| Loop::Continue(waited + wait)))
+						}
+						_ => Box::new(future::result(result.map(Loop::Break))),
+					}
+				})
+			})
+		}))
+	}
 }
@@ -2,7 +2,20 @@ use failure::Error;
 use hyper::{Body, Request};
 use json::Value;
 
-use App;
+use {App, AsyncApp, RedditFuture};
+
+impl AsyncApp {
+	/// Gets information about a user that is not currently authorized
+	/// # Arguments
+	/// * `name` - username of the user to query
+	/// # Returns
+	/// A future of a json value containing the user info
+	pub fn get_user(&self, name: &str) -> RedditFuture<Value> {
+		let req = Request::get(self.conn.read_url(&format!("/user/{}/about/.json", name))).body(Body::empty()).unwrap();
+
+		self.send_read(req)
+	}
+}
 
 impl App {
 	/// Gets information about a user that is not currently authorized
@@ -11,8 +24,6 @@ impl App {
 	/// # Returns
 	/// A json value containing the user info
 	pub fn get_user(&self, name: &str) -> Result<Value, Error> {
-		let req = Request::get(format!("https://www.reddit.com/user/{}/about/.json", name)).body(Body::empty()).unwrap();
-
-		self.conn.run_request(req)
+		self.block_on(self.inner.get_user(name))
 	}
 }
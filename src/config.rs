@@ -0,0 +1,322 @@
+//! # Config files
+//! Instead of hardcoding credentials, an app can be created from a site section of a
+//! [praw.ini](https://praw.readthedocs.io/en/latest/getting_started/configuration/prawini.html)
+//! file, the same file PRAW reads, so bots written with either can share it.
+//!
+//! ```ini
+//! [DEFAULT]
+//! user_agent = linux:mybot:v1.0 (by /u/IntrepidPig)
+//!
+//! [mybot]
+//! client_id = SI8pN3DSbt0zor
+//! client_secret = xaxkj7HNh8kwg8e5t4m6KvSrbTI
+//! username = mybot
+//! password = hunter2
+//! ```
+//!
+//! Settings in the `[DEFAULT]` section apply to every site. `praw.ini` is read from the user
+//! config directory (`$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`) and then the current
+//! directory, and settings in the current directory win. After that, environment variables named
+//! `praw_` followed by the setting, like `praw_client_secret`, override the file. `praw_site`
+//! picks the site when none is given.
+//!
+//! The app is authorized depending on which settings there are:
+//!
+//! * with a `refresh_token`, from the refresh token, as a web app if there is a `client_secret`
+//! and as an installed app otherwise
+//! * with a `username` and `password`, as a script. Orca also reads a `totp_secret` for accounts
+//! with two-factor authentication, which PRAW ignores.
+//! * with a `client_secret`, as the app alone
+//! * with only a `client_id`, as an installed app alone
+//!
+//! Without a `client_id` the app isn't authorized. `oauth_url` and `reddit_url` change the hosts
+//! requests are sent to, and `https_proxy` sets the proxy to send them through instead of the one
+//! in the environment. Values aren't interpolated.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use failure::Error;
+use futures::{future, Future};
+
+use app::{App, AppBuilder, AsyncApp, RedditFuture};
+use net::auth::OAuth;
+use net::proxy::Proxy;
+use net::transport::Transport;
+use net::Hosts;
+
+/// The name of the config file
+const FILE_NAME: &str = "praw.ini";
+
+/// The section whose settings apply to every site
+const DEFAULT_SITE: &str = "DEFAULT";
+
+/// Settings that can be overridden by environment variables
+const KEYS: &[&str] = &["client_id", "client_secret", "username", "password", "totp_secret", "user_agent", "refresh_token", "redirect_uri", "oauth_url", "reddit_url", "https_proxy"];
+
+/// The settings of a site section of a praw.ini file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+	site: String,
+	settings: HashMap<String, String>,
+}
+
+/// The sections of an ini file, with their settings
+type Sections = HashMap<String, HashMap<String, String>>;
+
+impl Config {
+	/// Loads a site from the praw.ini files in the user config directory and the current
+	/// directory, and applies overrides from the environment
+	/// # Arguments
+	/// * `site` - The name of the site section, or `None` to use `praw_site` from the environment
+	///   or else `DEFAULT`
+	pub fn load<'a, S: Into<Option<&'a str>>>(site: S) -> Result<Config, Error> {
+		let site = match site.into() {
+			Some(site) => site.to_string(),
+			None => env_var("site").unwrap_or_else(|| DEFAULT_SITE.to_string()),
+		};
+
+		let mut sections = Sections::new();
+		let paths = config_paths();
+		for path in &paths {
+			let contents = match read_file(path) {
+				Ok(contents) => contents,
+				Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+				Err(e) => return Err(format_err!("Failed to read {}: {}", path.display(), e)),
+			};
+			trace!("Reading config from {}", path.display());
+			for (name, settings) in parse_ini(&contents).map_err(|e| format_err!("{} in {}", e, path.display()))? {
+				sections.entry(name).or_default().extend(settings);
+			}
+		}
+
+		let mut config = Config::from_sections(sections, &site).map_err(|e| format_err!("{} (looked in {})", e, paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")))?;
+		config.override_from_env();
+		Ok(config)
+	}
+
+	/// Reads a site from a config file. The environment isn't looked at.
+	/// # Arguments
+	/// * `path` - The config file to read
+	/// * `site` - The name of the site section
+	pub fn from_file<P: AsRef<Path>>(path: P, site: &str) -> Result<Config, Error> {
+		let path = path.as_ref();
+		let contents = read_file(path).map_err(|e| format_err!("Failed to read {}: {}", path.display(), e))?;
+		Config::parse(&contents, site).map_err(|e| format_err!("{} in {}", e, path.display()))
+	}
+
+	/// Reads a site from the contents of a config file. The environment isn't looked at.
+	/// # Arguments
+	/// * `contents` - The contents of the config file
+	/// * `site` - The name of the site section
+	pub fn parse(contents: &str, site: &str) -> Result<Config, Error> {
+		Config::from_sections(parse_ini(contents)?, site)
+	}
+
+	fn from_sections(mut sections: Sections, site: &str) -> Result<Config, Error> {
+		let mut settings = sections.remove(DEFAULT_SITE).unwrap_or_default();
+		if site != DEFAULT_SITE {
+			match sections.remove(site) {
+				Some(site_settings) => settings.extend(site_settings),
+				None => return Err(format_err!("No [{}] site section in the config", site)),
+			}
+		}
+
+		Ok(Config { site: site.to_string(), settings })
+	}
+
+	/// Replaces settings with the environment variables named `praw_` followed by the setting
+	/// (or the same in uppercase) that are set
+	pub fn override_from_env(&mut self) {
+		self.override_with(env_var);
+	}
+
+	pub(crate) fn override_with<F: Fn(&str) -> Option<String>>(&mut self, lookup: F) {
+		for key in KEYS {
+			if let Some(value) = lookup(key) {
+				self.set(key, &value);
+			}
+		}
+	}
+
+	/// Returns the name of the site section
+	pub fn site(&self) -> &str {
+		&self.site
+	}
+
+	/// Returns a setting, or `None` if it isn't set or is empty
+	/// # Arguments
+	/// * `key` - The name of the setting, like `client_id`
+	pub fn get(&self, key: &str) -> Option<&str> {
+		match self.settings.get(&key.to_lowercase()) {
+			Some(value) if !value.is_empty() => Some(value),
+			_ => None,
+		}
+	}
+
+	/// Changes a setting
+	/// # Arguments
+	/// * `key` - The name of the setting, like `client_id`
+	/// * `value` - The new value
+	pub fn set(&mut self, key: &str, value: &str) {
+		self.settings.insert(key.to_lowercase(), value.to_string());
+	}
+
+	/// Creates an app with the user agent of the config and authorizes it with its credentials
+	pub fn app(&self) -> Result<App, Error> {
+		self.build(self.builder()?)
+	}
+
+	/// Creates an app that sends its requests through a custom transport, with the user agent of
+	/// the config, and authorizes it with its credentials
+	/// # Arguments
+	/// * `transport` - The transport to send requests with
+	pub fn app_with_transport(&self, transport: Arc<dyn Transport>) -> Result<App, Error> {
+		self.build(self.builder()?.transport(transport))
+	}
+
+	/// Returns a builder with the user agent and hosts of the config, so other options can be set
+	/// before creating the app. The app isn't authorized when it's built.
+	pub fn builder(&self) -> Result<AppBuilder, Error> {
+		let mut builder = match self.get("user_agent") {
+			Some(user_agent) => AppBuilder::new().user_agent(user_agent),
+			None => return Err(format_err!("user_agent must be set for the [{}] site", self.site)),
+		};
+
+		if self.get("oauth_url").is_some() || self.get("reddit_url").is_some() {
+			let default = Hosts::default();
+			let reddit = self.get("reddit_url");
+			builder = builder.hosts(Hosts::new(self.get("oauth_url").unwrap_or(&default.oauth), reddit.unwrap_or(&default.public), reddit.unwrap_or(&default.token)));
+		}
+		if let Some(proxy) = self.get("https_proxy") {
+			builder = builder.proxy(Some(Proxy::new(proxy)?));
+		}
+
+		Ok(builder)
+	}
+
+	/// Builds an app and authorizes it
+	fn build(&self, builder: AppBuilder) -> Result<App, Error> {
+		let app = builder.build_async()?;
+		app.conn.run(self.authorize_async(&app))?;
+		Ok(App::from(app))
+	}
+
+	/// Authorizes an app with the credentials of the config
+	/// # Arguments
+	/// * `app` - The app to authorize
+	pub fn authorize(&self, app: &mut App) -> Result<(), Error> {
+		app.conn().run(self.authorize_async(app.as_async()))
+	}
+
+	/// Authorizes an app with the credentials of the config, without blocking
+	/// # Arguments
+	/// * `app` - The app to authorize
+	pub fn authorize_async(&self, app: &AsyncApp) -> RedditFuture<()> {
+		let id = match self.get("client_id") {
+			Some(id) => id,
+			None => return Box::new(future::ok(())),
+		};
+
+		match (self.get("client_secret"), self.get("refresh_token"), self.get("username"), self.get("password")) {
+			(Some(secret), Some(refresh_token), _, _) => {
+				let auth = OAuth::WebApp {
+					id: id.to_string(),
+					secret: secret.to_string(),
+					redirect: self.get("redirect_uri").unwrap_or("").to_string(),
+					token: String::new(),
+					refresh_token: Some(refresh_token.to_string()),
+					expire_instant: None,
+					scopes: None,
+				};
+				let conn = Arc::clone(&app.conn);
+				Box::new(OAuth::renew_async(Arc::clone(&app.conn), auth).map(move |auth| conn.set_auth(auth)))
+			}
+			(None, Some(refresh_token), _, _) => app.authorize_with_refresh_token(id, refresh_token),
+			(Some(secret), None, Some(username), Some(password)) => match self.get("totp_secret") {
+				Some(totp_secret) => app.authorize_script_with_totp(id, secret, username, password, totp_secret),
+				None => app.authorize_script(id, secret, username, password),
+			},
+			(None, None, Some(_), Some(_)) => Box::new(future::err(format_err!("client_secret must be set to authorize the [{}] site as a script", self.site))),
+			(Some(secret), None, _, _) => app.authorize_application(id, secret),
+			(None, None, _, _) => app.authorize_installed_client(id, "DO_NOT_TRACK_THIS_DEVICE"),
+		}
+	}
+}
+
+/// Parses the sections of an ini file the way python's configparser does, without interpolation
+fn parse_ini(contents: &str) -> Result<Sections, Error> {
+	let mut sections = Sections::new();
+	let mut section: Option<String> = None;
+	let mut key: Option<String> = None;
+
+	for (number, line) in contents.lines().enumerate() {
+		let trimmed = line.trim();
+		if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+			key = None;
+			continue;
+		}
+
+		// Indented lines continue the value of the setting above
+		if line.starts_with(|c: char| c.is_whitespace()) {
+			if let Some(value) = section.as_ref().and_then(|section| sections.get_mut(section)).and_then(|settings| key.as_ref().and_then(move |key| settings.get_mut(key))) {
+				value.push('\n');
+				value.push_str(trimmed);
+				continue;
+			}
+		}
+
+		if trimmed.starts_with('[') && trimmed.ends_with(']') {
+			let name = trimmed[1..trimmed.len() - 1].trim().to_string();
+			sections.entry(name.clone()).or_default();
+			section = Some(name);
+			key = None;
+			continue;
+		}
+
+		let settings = match section {
+			Some(ref section) => sections.get_mut(section).expect("current section exists"),
+			None => return Err(format_err!("Line {} of the config comes before any [section]", number + 1)),
+		};
+		match trimmed.find(&['=', ':'][..]) {
+			Some(i) => {
+				let name = trimmed[..i].trim().to_lowercase();
+				settings.insert(name.clone(), trimmed[i + 1..].trim().to_string());
+				key = Some(name);
+			}
+			None => return Err(format_err!("Line {} of the config isn't a section, setting or comment", number + 1)),
+		}
+	}
+
+	Ok(sections)
+}
+
+/// Returns the config files to read, from lowest to highest priority
+fn config_paths() -> Vec<PathBuf> {
+	let mut paths = Vec::new();
+	let config_dir = if cfg!(windows) {
+		env::var_os("APPDATA").map(PathBuf::from)
+	} else {
+		env::var_os("XDG_CONFIG_HOME").map(PathBuf::from).or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+	};
+	if let Some(dir) = config_dir {
+		paths.push(dir.join(FILE_NAME));
+	}
+	paths.push(PathBuf::from(FILE_NAME));
+	paths
+}
+
+fn read_file(path: &Path) -> Result<String, ::std::io::Error> {
+	let mut contents = String::new();
+	File::open(path)?.read_to_string(&mut contents)?;
+	Ok(contents)
+}
+
+/// Reads the environment variable overriding a setting, `praw_<key>` or `PRAW_<KEY>`
+fn env_var(key: &str) -> Option<String> {
+	env::var(format!("praw_{}", key)).or_else(|_| env::var(format!("PRAW_{}", key.to_uppercase()))).ok()
+}
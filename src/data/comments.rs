@@ -1,7 +1,7 @@
 use json;
 use json::Value;
 
-use data::{Listing, Thing};
+use data::{Listing, MoreComments, Thing};
 use errors::ParseError;
 use failure::{err_msg, Error};
 use App;
@@ -55,13 +55,26 @@ pub struct Comment {
 
 impl Thing for Comment {
 	fn from_value(val: &Value, app: &App) -> Result<Comment, Error> {
+		let mut mores = Vec::new();
+		let mut comment = Comment::parse(val, &mut mores)?;
+		let link_id = comment.link_id.clone();
+		comment.replies.insert_more(&link_id, mores, app)?;
+
+		Ok(comment)
+	}
+}
+
+impl Comment {
+	/// Parses a comment from json without fetching any replies that weren't loaded. Those are
+	/// pushed to `mores` instead.
+	pub(crate) fn parse(val: &Value, mores: &mut Vec<MoreComments>) -> Result<Comment, Error> {
 		// nice
 		macro_rules! out {
 			($val:ident) => {
 				return Err(Error::from(ParseError {
 					thing_type: "Comment".to_string(),
 					json: json::to_string_pretty($val).unwrap(),
-				}));
+				}))
 			};
 		}
 
@@ -125,7 +138,7 @@ impl Thing for Comment {
 		};
 		let replies: Listing<Comment> = match val["replies"] {
 			Value::String(_) => Listing::new(),
-			Value::Object(_) => Listing::from_value(&val["replies"]["data"]["children"], &link_id, app).unwrap(),
+			Value::Object(_) => Listing::parse(&val["replies"]["data"]["children"], mores)?,
 			_ => return Err(err_msg(format!("Unexpected value for \"replies\": {}", val["replies"]))),
 		};
 
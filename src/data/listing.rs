@@ -4,13 +4,13 @@ use std::default::Default;
 use json;
 use json::Value;
 
-use data::{Comment, Thing};
+use data::{Comment, Post};
 use App;
 
 use errors::ParseError;
 use failure::Error;
 
-/// A listing of Things. Has special implementations, currently just for Comments.
+/// A listing of Things. Has special implementations, currently for Comments and Posts.
 #[derive(Debug, Clone)]
 pub struct Listing<T> {
 	/// The contents of the Listing
@@ -79,13 +79,38 @@ impl Listing<Comment> {
 
 	/// Parses the listing from json, fetching more comments as necessary.
 	pub fn from_value(listing_data: &Value, post_id: &str, app: &App) -> Result<Listing<Comment>, Error> {
+		let mut mores = Vec::new();
+		let mut listing = Listing::parse(listing_data, &mut mores)?;
+		listing.insert_more(post_id, mores, app)?;
+
+		Ok(listing)
+	}
+
+	/// Loads the comments that were not present in a listing and inserts them in their places in
+	/// the tree.
+	pub(crate) fn insert_more(&mut self, post_id: &str, mores: Vec<MoreComments>, app: &App) -> Result<(), Error> {
+		for more in mores {
+			let children = more.children.iter().map(|c| c.as_str()).collect::<Vec<&str>>();
+			for child in app.more_children(post_id, &more.id, &children)? {
+				self.insert_comment(child);
+			}
+			trace!("Successfully got children");
+		}
+
+		Ok(())
+	}
+
+	/// Parses the listing from json without fetching any more comments. The `more` objects found
+	/// in the listing (including in the replies of comments) are pushed to `mores` so they can be
+	/// loaded afterwards.
+	pub(crate) fn parse(listing_data: &Value, mores: &mut Vec<MoreComments>) -> Result<Listing<Comment>, Error> {
 		let mut listing: Listing<Comment> = Listing::new();
 
 		if let Some(array) = listing_data.as_array() {
 			for item in array {
 				let kind = item["kind"].as_str().unwrap();
 				if kind == "t1" {
-					listing.children.push_back(if let Ok(c) = Comment::from_value(item, app) {
+					listing.children.push_back(if let Ok(c) = Comment::parse(item, mores) {
 						c
 					} else {
 						return Err(Error::from(ParseError {
@@ -98,11 +123,10 @@ impl Listing<Comment> {
 					let more_id = item["data"]["id"].as_str().unwrap();
 					if !more.is_empty() {
 						debug!("Need some children {}", json::to_string_pretty(more).unwrap());
-						let more = more.iter().map(|i| i.as_str().unwrap()).collect::<Vec<&str>>();
-						for child in app.more_children(post_id, more_id, &more)? {
-							listing.children.push_back(child);
-						}
-						trace!("Successfully got children");
+						mores.push(MoreComments {
+							id: more_id.to_string(),
+							children: more.iter().map(|i| i.as_str().unwrap().to_string()).collect(),
+						});
 					}
 				}
 			}
@@ -116,3 +140,36 @@ impl Listing<Comment> {
 		}
 	}
 }
+
+impl Listing<Post> {
+	/// Parses a listing of posts from json. The comments on the posts aren't loaded.
+	pub(crate) fn parse_posts(listing_data: &Value) -> Result<Listing<Post>, Error> {
+		let array = match listing_data.as_array() {
+			Some(array) => array,
+			None => {
+				return Err(Error::from(ParseError {
+					thing_type: "Listing<Post>".to_string(),
+					json: json::to_string_pretty(listing_data).unwrap(),
+				}))
+			}
+		};
+
+		let mut listing = Listing::new();
+		for item in array {
+			if item["kind"] == "t3" {
+				listing.children.push_back(Post::parse_data(&item["data"], Listing::new())?);
+			}
+		}
+
+		Ok(listing)
+	}
+}
+
+/// Comments that were left out of a listing and have to be loaded with a morechildren request
+#[derive(Debug, Clone)]
+pub(crate) struct MoreComments {
+	/// The id of the more object
+	pub id: String,
+	/// The ids of the comments that need to be loaded
+	pub children: Vec<String>,
+}
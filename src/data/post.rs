@@ -1,14 +1,39 @@
+use chrono::{DateTime, TimeZone, Utc};
+
 use data::{Comment, Listing, Thing};
 use errors::ParseError;
 use failure::Error;
 use json::{self, Value};
 use App;
 
+/// The type of content a post has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostKind {
+	/// A link to another website
+	Link,
+	/// A text post
+	SelfPost,
+	/// A single image
+	Image,
+	/// A video, either hosted by reddit or embedded from another site
+	Video,
+	/// A gallery of several images
+	Gallery,
+	/// A poll
+	Poll,
+	/// A crosspost of another post
+	Crosspost,
+}
+
 /// A struct that represents a submission to reddit
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Post {
 	/// Id of the post
 	pub id: String,
+	/// The fullname of the post (includes the t3_ prefix)
+	pub name: String,
+	/// The type of content the post has
+	pub kind: PostKind,
 	/// Title of the post
 	pub title: String,
 	/// Author of the post
@@ -25,75 +50,279 @@ pub struct Post {
 	pub num_comments: i64,
 	/// Url of the post
 	pub url: String,
+	/// The path of the comments page of the post, like `/r/rust/comments/7am0zo/title/`, or
+	/// `/comments/7am0zo/` if reddit left it out
+	pub permalink: String,
+	/// When the post was submitted, if reddit included it
+	pub created_utc: Option<DateTime<Utc>>,
+	/// The domain the post links to, or `self.<subreddit>` for text posts
+	pub domain: String,
+	/// The text of the post in markdown, empty if it isn't a text post
+	pub selftext: String,
+	/// The text of the post rendered to HTML
+	pub selftext_html: Option<String>,
+	/// Whether the post is a text post
+	pub is_self: bool,
+	/// Whether the post is marked NSFW
+	pub over_18: bool,
+	/// Whether the post is marked as a spoiler
+	pub spoiler: bool,
+	/// Whether the post is locked, so it can't be commented on
+	pub locked: bool,
+	/// Whether the post is archived, so it can't be voted or commented on
+	pub archived: bool,
 	/// Whether the post is stickied
 	pub stickied: bool,
 	/// Amount of times this post has been gilded
 	pub gilded: i64,
-	/// The comments on this post
+	/// How the post is distinguished, like `moderator` or `admin`
+	pub distinguished: Option<String>,
+	/// The flair of the post
+	pub link_flair: Option<Flair>,
+	/// The flair of the author in the subreddit
+	pub author_flair: Option<Flair>,
+	/// Url of the thumbnail of the post, if it has one
+	pub thumbnail: Option<String>,
+	/// Preview images of the content of the post
+	pub preview: Vec<PreviewImage>,
+	/// The media embedded in the post
+	pub media: Option<Media>,
+	/// The media embedded in the post, with HTTPS urls
+	pub secure_media: Option<Media>,
+	/// The images in the post, if it's a gallery
+	pub gallery: Vec<GalleryItem>,
+	/// The poll in the post, if it's a poll
+	pub poll: Option<Poll>,
+	/// The fullname of the post this is a crosspost of
+	pub crosspost_parent: Option<String>,
+	/// The post this is a crosspost of, if reddit included it
+	pub crosspost_parent_post: Option<Box<Post>>,
+	/// The comments on this post. Empty for posts loaded in a listing.
 	pub comments: Listing<Comment>,
 }
 
+/// The flair of a post or of a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flair {
+	/// The text of the flair
+	pub text: Option<String>,
+	/// The CSS class of the flair
+	pub css_class: Option<String>,
+	/// The id of the flair template the flair was chosen from
+	pub template_id: Option<String>,
+	/// The background color of the flair, like `#ff4500`
+	pub background_color: Option<String>,
+	/// Whether the text of the flair is `dark` or `light`
+	pub text_color: Option<String>,
+}
+
+/// An image hosted by reddit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+	/// Url of the image
+	pub url: String,
+	/// Width of the image in pixels
+	pub width: i64,
+	/// Height of the image in pixels
+	pub height: i64,
+}
+
+/// A preview image of the content of a post, in several sizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviewImage {
+	/// Id of the preview
+	pub id: String,
+	/// The image at its original size
+	pub source: Image,
+	/// Smaller versions of the image
+	pub resolutions: Vec<Image>,
+}
+
+/// Media embedded in a post
+#[derive(Debug, Clone, PartialEq)]
+pub enum Media {
+	/// A video hosted by reddit
+	RedditVideo(RedditVideo),
+	/// Content embedded from another site, like a YouTube video
+	Embed(Embed),
+}
+
+/// A video hosted by reddit
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedditVideo {
+	/// Url of the video without sound, playable without streaming support
+	pub fallback_url: String,
+	/// Url of the HLS stream of the video
+	pub hls_url: Option<String>,
+	/// Url of the DASH stream of the video
+	pub dash_url: Option<String>,
+	/// Width of the video in pixels
+	pub width: i64,
+	/// Height of the video in pixels
+	pub height: i64,
+	/// Length of the video in seconds
+	pub duration: i64,
+	/// Whether the video was uploaded as a gif
+	pub is_gif: bool,
+}
+
+/// Content embedded from another site
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embed {
+	/// The site the content is from, like `youtube.com`
+	pub provider: String,
+	/// The title of the content
+	pub title: Option<String>,
+	/// HTML that embeds the content in a page
+	pub html: Option<String>,
+	/// Url of a thumbnail of the content
+	pub thumbnail_url: Option<String>,
+}
+
+/// An image in a gallery post
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GalleryItem {
+	/// Id of the image
+	pub media_id: String,
+	/// The caption of the image
+	pub caption: Option<String>,
+	/// The link attached to the image
+	pub outbound_url: Option<String>,
+	/// The image, if reddit has finished processing it
+	pub source: Option<Image>,
+}
+
+/// A poll in a post
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Poll {
+	/// The options that can be voted for
+	pub options: Vec<PollOption>,
+	/// The number of votes, if they can be seen yet
+	pub total_vote_count: Option<i64>,
+	/// When voting ends
+	pub voting_end: DateTime<Utc>,
+	/// The id of the option the current user voted for
+	pub user_selection: Option<String>,
+}
+
+/// An option in a poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PollOption {
+	/// Id of the option
+	pub id: String,
+	/// The text of the option
+	pub text: String,
+	/// The number of votes for the option, if they can be seen yet
+	pub vote_count: Option<i64>,
+}
+
 impl Thing for Post {
 	fn from_value(val: &Value, app: &App) -> Result<Post, Error> {
-		let post = &val["data"]["children"][0]["data"];
+		let comments = app.get_comment_tree(&Post::parse_id(val)?)?;
+
+		Post::parse(val, comments)
+	}
+}
+
+impl Post {
+	/// Gets the id of the post in a by_id response
+	pub(crate) fn parse_id(val: &Value) -> Result<String, Error> {
+		match val["data"]["children"][0]["data"]["id"].as_str() {
+			Some(t) => Ok(t.to_string()),
+			None => Err(Error::from(ParseError {
+				thing_type: "Post".to_string(),
+				json: json::to_string_pretty(val).unwrap(),
+			})),
+		}
+	}
+
+	/// Parses a post from a by_id response, with the comments on it already loaded
+	pub(crate) fn parse(val: &Value, comments: Listing<Comment>) -> Result<Post, Error> {
+		Post::parse_data(&val["data"]["children"][0]["data"], comments)
+	}
 
+	/// Parses a post from the data of a t3 thing
+	pub(crate) fn parse_data(post: &Value, comments: Listing<Comment>) -> Result<Post, Error> {
 		macro_rules! out {
 			($val:ident) => {
 				return Err(Error::from(ParseError {
 					thing_type: "Post".to_string(),
 					json: json::to_string_pretty($val).unwrap(),
-				}));
+				}))
 			};
 		}
 
 		let id = match post["id"].as_str() {
 			Some(t) => t.to_string(),
-			None => out!(val),
+			None => out!(post),
 		};
 		let title = match post["title"].as_str() {
 			Some(t) => t.to_string(),
-			None => out!(val),
+			None => out!(post),
 		};
-		let author = match post["author"].as_str() {
-			Some(t) => t.to_string(),
-			None => out!(val),
-		};
-		let subreddit = match post["subreddit"].as_str() {
-			Some(t) => t.to_string(),
-			None => out!(val),
-		};
-		let ups = match post["ups"].as_i64() {
-			Some(t) => t,
-			None => out!(val),
-		};
-		let downs = match post["downs"].as_i64() {
-			Some(t) => t,
-			None => out!(val),
-		};
-		let score = match post["score"].as_i64() {
-			Some(t) => t,
-			None => out!(val),
-		};
-		let num_comments = match post["num_comments"].as_i64() {
-			Some(t) => t,
-			None => out!(val),
-		};
-		let url = match post["url"].as_str() {
-			Some(t) => t.to_string(),
-			None => out!(val),
+
+		// Reddit leaves fields out of removed and promoted posts, so only the id and title are required
+		let str_or = |key: &str, default: &str| post[key].as_str().unwrap_or(default).to_string();
+		let name = string(&post["name"]).unwrap_or_else(|| format!("t3_{}", id));
+		let author = str_or("author", "[deleted]");
+		let subreddit = str_or("subreddit", "");
+		let ups = post["ups"].as_i64().unwrap_or(0);
+		let downs = post["downs"].as_i64().unwrap_or(0);
+		let score = post["score"].as_i64().unwrap_or(0);
+		let num_comments = post["num_comments"].as_i64().unwrap_or(0);
+		let url = str_or("url", "");
+		let permalink = string(&post["permalink"]).unwrap_or_else(|| format!("/comments/{}/", id));
+		let created_utc = post["created_utc"].as_f64().and_then(timestamp);
+		let domain = str_or("domain", "");
+		let is_self = post["is_self"].as_bool().unwrap_or(false);
+		let over_18 = post["over_18"].as_bool().unwrap_or(false);
+		let stickied = post["stickied"].as_bool().unwrap_or(false);
+		let gilded = post["gilded"].as_i64().unwrap_or(0);
+
+		let selftext = str_or("selftext", "");
+		let selftext_html = string(&post["selftext_html"]);
+		let spoiler = post["spoiler"].as_bool().unwrap_or(false);
+		let locked = post["locked"].as_bool().unwrap_or(false);
+		let archived = post["archived"].as_bool().unwrap_or(false);
+		let distinguished = string(&post["distinguished"]);
+		let link_flair = Flair::parse(post, "link_flair");
+		let author_flair = Flair::parse(post, "author_flair");
+		// Posts without a thumbnail have a placeholder like "self" or "default" instead of a url
+		let thumbnail = string(&post["thumbnail"]).filter(|t| t.starts_with("http"));
+		let preview = match post["preview"]["images"].as_array() {
+			Some(images) => images.iter().filter_map(PreviewImage::parse).collect(),
+			None => Vec::new(),
 		};
-		let stickied = match post["stickied"].as_bool() {
-			Some(t) => t,
-			None => out!(val),
+		let media = Media::parse(&post["media"]);
+		let secure_media = Media::parse(&post["secure_media"]);
+		let gallery = GalleryItem::parse_all(post);
+		let poll = Poll::parse(&post["poll_data"]);
+		let crosspost_parent = string(&post["crosspost_parent"]);
+		let crosspost_parent_post = match post["crosspost_parent_list"][0] {
+			Value::Object(_) => Some(Box::new(Post::parse_data(&post["crosspost_parent_list"][0], Listing::new())?)),
+			_ => None,
 		};
-		let gilded = match post["gilded"].as_i64() {
-			Some(t) => t,
-			None => out!(val),
+
+		let kind = if crosspost_parent.is_some() {
+			PostKind::Crosspost
+		} else if post["is_gallery"].as_bool().unwrap_or(false) || !gallery.is_empty() {
+			PostKind::Gallery
+		} else if poll.is_some() {
+			PostKind::Poll
+		} else if is_self {
+			PostKind::SelfPost
+		} else if post["is_video"].as_bool().unwrap_or(false) || post["post_hint"].as_str().is_some_and(|hint| hint.ends_with(":video")) {
+			PostKind::Video
+		} else if post["post_hint"] == "image" || domain == "i.redd.it" {
+			PostKind::Image
+		} else {
+			PostKind::Link
 		};
-		let comments = app.get_comment_tree(&id)?;
 
 		Ok(Post {
 			id,
+			name,
+			kind,
 			title,
 			author,
 			subreddit,
@@ -102,9 +331,162 @@ impl Thing for Post {
 			score,
 			num_comments,
 			url,
+			permalink,
+			created_utc,
+			domain,
+			selftext,
+			selftext_html,
+			is_self,
+			over_18,
+			spoiler,
+			locked,
+			archived,
 			stickied,
 			gilded,
+			distinguished,
+			link_flair,
+			author_flair,
+			thumbnail,
+			preview,
+			media,
+			secure_media,
+			gallery,
+			poll,
+			crosspost_parent,
+			crosspost_parent_post,
 			comments,
 		})
 	}
 }
+
+impl Flair {
+	/// Parses the flair fields of a post starting with `prefix`, like `link_flair`
+	fn parse(post: &Value, prefix: &str) -> Option<Flair> {
+		let field = |name: &str| string(&post[&format!("{}_{}", prefix, name)]).filter(|t| !t.is_empty());
+		let flair = Flair {
+			text: field("text"),
+			css_class: field("css_class"),
+			template_id: field("template_id"),
+			background_color: field("background_color"),
+			text_color: field("text_color"),
+		};
+
+		if flair.text.is_none() && flair.css_class.is_none() && flair.template_id.is_none() {
+			None
+		} else {
+			Some(flair)
+		}
+	}
+}
+
+impl Image {
+	/// Parses an image from an object with `u` or `url`, `x` or `width` and `y` or `height` fields
+	fn parse(val: &Value) -> Option<Image> {
+		let url = val["url"].as_str().or_else(|| val["u"].as_str()).or_else(|| val["gif"].as_str())?;
+		Some(Image {
+			url: unescape(url),
+			width: val["width"].as_i64().or_else(|| val["x"].as_i64())?,
+			height: val["height"].as_i64().or_else(|| val["y"].as_i64())?,
+		})
+	}
+}
+
+impl PreviewImage {
+	fn parse(val: &Value) -> Option<PreviewImage> {
+		Some(PreviewImage {
+			id: val["id"].as_str()?.to_string(),
+			source: Image::parse(&val["source"])?,
+			resolutions: match val["resolutions"].as_array() {
+				Some(resolutions) => resolutions.iter().filter_map(Image::parse).collect(),
+				None => Vec::new(),
+			},
+		})
+	}
+}
+
+impl Media {
+	fn parse(val: &Value) -> Option<Media> {
+		let video = &val["reddit_video"];
+		if video.is_object() {
+			return Some(Media::RedditVideo(RedditVideo {
+				fallback_url: video["fallback_url"].as_str()?.to_string(),
+				hls_url: string(&video["hls_url"]),
+				dash_url: string(&video["dash_url"]),
+				width: video["width"].as_i64().unwrap_or(0),
+				height: video["height"].as_i64().unwrap_or(0),
+				duration: video["duration"].as_i64().unwrap_or(0),
+				is_gif: video["is_gif"].as_bool().unwrap_or(false),
+			}));
+		}
+
+		let oembed = &val["oembed"];
+		Some(Media::Embed(Embed {
+			provider: val["type"].as_str()?.to_string(),
+			title: string(&oembed["title"]),
+			html: string(&oembed["html"]),
+			thumbnail_url: string(&oembed["thumbnail_url"]),
+		}))
+	}
+}
+
+impl GalleryItem {
+	/// Parses the images of a gallery post, in the order they're shown
+	fn parse_all(post: &Value) -> Vec<GalleryItem> {
+		let items = match post["gallery_data"]["items"].as_array() {
+			Some(items) => items,
+			None => return Vec::new(),
+		};
+
+		items
+			.iter()
+			.filter_map(|item| {
+				let media_id = item["media_id"].as_str()?;
+				Some(GalleryItem {
+					media_id: media_id.to_string(),
+					caption: string(&item["caption"]),
+					outbound_url: string(&item["outbound_url"]),
+					source: Image::parse(&post["media_metadata"][media_id]["s"]),
+				})
+			})
+			.collect()
+	}
+}
+
+impl Poll {
+	fn parse(val: &Value) -> Option<Poll> {
+		let options = val["options"].as_array()?.iter().filter_map(PollOption::parse).collect();
+		// The end of voting is given in milliseconds
+		let voting_end = timestamp(val["voting_end_timestamp"].as_f64()? / 1000.0)?;
+
+		Some(Poll {
+			options,
+			total_vote_count: val["total_vote_count"].as_i64(),
+			voting_end,
+			user_selection: string(&val["user_selection"]),
+		})
+	}
+}
+
+impl PollOption {
+	fn parse(val: &Value) -> Option<PollOption> {
+		Some(PollOption {
+			id: val["id"].as_str()?.to_string(),
+			text: val["text"].as_str()?.to_string(),
+			vote_count: val["vote_count"].as_i64(),
+		})
+	}
+}
+
+/// Converts a unix timestamp in seconds to a date
+fn timestamp(secs: f64) -> Option<DateTime<Utc>> {
+	Utc.timestamp_opt(secs.trunc() as i64, (secs.fract() * 1e9) as u32).single()
+}
+
+fn string(val: &Value) -> Option<String> {
+	val.as_str().map(str::to_string)
+}
+
+/// Reddit escapes urls in json like they're in HTML, unless `raw_json=1` is given
+fn unescape(url: &str) -> String {
+	url.replace("&amp;", "&")
+}
@@ -1,15 +1,25 @@
 use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
 
 use data::Comment;
+use errors::RedditError;
 use App;
 
 /// A struct that represents a stream of comments from a subreddit as they are posted. To use it
 /// simply create a `for` loop with this is the source. It will automatically retrieve comments
 /// as needed. The subreddit can be `all` to create a stream of comments from all of reddit.
+/// Transient errors loading comments, like timeouts, dropped connections and reddit being down,
+/// are logged and the request is tried again, backing off while they keep happening. Any other
+/// error, like a missing scope or a forbidden subreddit, is logged and ends the stream.
 pub struct Comments<'a> {
 	sub: String,
 	cache: VecDeque<Comment>,
 	last: Option<String>,
+	/// The number of times in a row loading comments has failed
+	failures: u32,
+	/// Whether a permanent error ended the stream
+	done: bool,
 	app: &'a App,
 }
 
@@ -23,13 +33,43 @@ impl<'a> Comments<'a> {
 		let cache: VecDeque<Comment> = VecDeque::new();
 		let last = None;
 
-		Comments { sub: sub.to_string(), cache, last, app }
+		Comments {
+			sub: sub.to_string(),
+			cache,
+			last,
+			failures: 0,
+			done: false,
+			app,
+		}
 	}
 
 	fn refresh(&mut self, app: &App) {
-		let mut resp = app.get_recent_comments(&self.sub, Some(500), self.last.as_ref().map(|s| s.as_str())).expect("Could not get recent comments");
+		// Nothing is loaded when a request fails, so the iterator just asks again unless it's done
+		let mut resp = match app.get_recent_comments(&self.sub, Some(500), self.last.as_deref()) {
+			Ok(resp) => resp,
+			Err(e) => {
+				match e.downcast_ref::<RedditError>() {
+					Some(&RedditError::Timeout { .. }) => warn!("Timed out getting recent comments, trying again"),
+					// Trying again won't help, so the stream ends
+					Some(err) if !err.is_transient() => {
+						error!("Could not get recent comments, ending the stream: {}", e);
+						self.done = true;
+					}
+					// Reddit being down and errors that aren't from reddit, like a dropped connection
+					_ => {
+						// Back off exponentially, up to about a minute, so a persistent error isn't hammered
+						self.failures += 1;
+						let delay = Duration::from_secs(1 << (self.failures - 1).min(6));
+						error!("Could not get recent comments, trying again in {}s: {}", delay.as_secs(), e);
+						thread::sleep(delay);
+					}
+				}
+				return;
+			}
+		};
+		self.failures = 0;
 
-		if let Some(comment) = resp.by_ref().peekable().peek() {
+		if let Some(comment) = resp.children.front() {
 			self.last = Some(comment.name.clone());
 		}
 
@@ -41,7 +81,7 @@ impl<'a> Iterator for Comments<'a> {
 	type Item = Comment;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		while self.cache.is_empty() {
+		while self.cache.is_empty() && !self.done {
 			self.refresh(self.app);
 		}
 		self.cache.pop_front()
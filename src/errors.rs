@@ -1,3 +1,8 @@
+use std::fmt;
+use std::time::Duration;
+
+use json::Value;
+
 /// An enum containing possible errors from a request to reddit
 #[derive(Debug, Fail)]
 pub enum RedditError {
@@ -29,9 +34,194 @@ pub enum RedditError {
 		/// The response that was recieved
 		response: String,
 	},
+	/// Reddit is overloaded or down (HTTP 429 or 5xx), and the request may work if it's sent again
+	#[fail(display = "\nReddit couldn't handle request {}, got response {}\n", request, response)]
+	ServerError {
+		/// The request that was sent
+		request: String,
+		/// The response that was recieved
+		response: String,
+	},
+	/// The token sent with a request was rejected (HTTP 401), usually because it expired
+	#[fail(display = "\nToken sent with request {} was rejected with response {}\n", request, response)]
+	Unauthorized {
+		/// The request that was sent
+		request: String,
+		/// The response that was recieved
+		response: String,
+	},
+	/// The authorization doesn't grant a scope needed for the request, so it wasn't sent
+	#[fail(display = "The authorization doesn't grant the {} scope", scope)]
+	MissingScope {
+		/// The name of the missing scope
+		scope: String,
+	},
+	/// The token expired and can't be refreshed, because only a temporary authorization was
+	/// requested. The app has to be authorized again.
+	#[fail(display = "The token expired and can't be refreshed, authorize again")]
+	TokenExpired,
 	/// Authorization failed
 	#[fail(display = "Failed to authorize")]
 	AuthError,
+	/// The state reddit redirected back with doesn't belong to an authorization in progress, or
+	/// was already used
+	#[fail(display = "The state of the redirect doesn't match an authorization in progress")]
+	MismatchedState,
+	/// Reddit didn't respond to a request in time
+	#[fail(display = "Request {} timed out after {:?}", request, timeout)]
+	Timeout {
+		/// The request that was sent
+		request: String,
+		/// How long the response was waited for
+		timeout: Duration,
+	},
+	/// Reddit rejected the request, even though it responded successfully. This is how reddit
+	/// reports failed submissions, comments and messages.
+	#[fail(display = "Reddit rejected the request with {}: {}", code, message)]
+	Api {
+		/// The error code reddit gave
+		code: ApiErrorCode,
+		/// The human readable explanation of the error
+		message: String,
+		/// The parameter of the request that caused the error, if any
+		field: Option<String>,
+	},
+}
+
+impl RedditError {
+	/// Whether the error is likely temporary, so sending the request again later might work.
+	/// This is the case for timeouts and responses from an overloaded reddit.
+	pub fn is_transient(&self) -> bool {
+		matches!(*self, RedditError::Timeout { .. } | RedditError::ServerError { .. })
+	}
+
+	/// How long reddit asked to wait before trying again, if this is a RATELIMIT error. Reddit only
+	/// says this in the error message, such as "you are doing that too much. try again in 5 minutes."
+	pub fn ratelimit_wait(&self) -> Option<Duration> {
+		let message = match *self {
+			RedditError::Api {
+				code: ApiErrorCode::Ratelimit,
+				ref message,
+				..
+			} => message,
+			_ => return None,
+		};
+
+		// Find the first number followed by a unit of time
+		let words = message.split_whitespace().collect::<Vec<&str>>();
+		for pair in words.windows(2) {
+			let amount = match pair[0].parse::<u64>() {
+				Ok(amount) => amount,
+				Err(_) => continue,
+			};
+			let unit = pair[1].trim_matches(|c: char| !c.is_alphabetic());
+			if unit.starts_with("millisecond") {
+				return Some(Duration::from_millis(amount));
+			} else if unit.starts_with("second") {
+				return Some(Duration::from_secs(amount));
+			} else if unit.starts_with("minute") {
+				return Some(Duration::from_secs(amount * 60));
+			} else if unit.starts_with("hour") {
+				return Some(Duration::from_secs(amount * 60 * 60));
+			}
+		}
+
+		None
+	}
+
+	/// Finds the first error in the `json.errors` array of a response, if there is one
+	pub(crate) fn from_api_errors(response: &Value) -> Option<RedditError> {
+		let error = response.get("json")?.get("errors")?.as_array()?.first()?;
+
+		Some(RedditError::Api {
+			code: ApiErrorCode::from_code(error.get(0).and_then(Value::as_str).unwrap_or("")),
+			message: error.get(1).and_then(Value::as_str).unwrap_or("").to_string(),
+			field: error.get(2).and_then(Value::as_str).map(|field| field.to_string()),
+		})
+	}
+}
+
+/// The error codes reddit responds with when it rejects a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiErrorCode {
+	/// Too many requests of this kind have been sent recently (`RATELIMIT`)
+	Ratelimit,
+	/// The subreddit doesn't exist (`SUBREDDIT_NOEXIST`)
+	SubredditNoexist,
+	/// The user isn't allowed to post in the subreddit (`SUBREDDIT_NOTALLOWED`)
+	SubredditNotallowed,
+	/// A parameter is too long (`TOO_LONG`)
+	TooLong,
+	/// A required parameter is missing (`NO_TEXT`)
+	NoText,
+	/// A message was sent without a subject (`NO_SUBJECT`)
+	NoSubject,
+	/// The user doesn't exist (`USER_DOESNT_EXIST`)
+	UserDoesntExist,
+	/// The thing being replied to was deleted. Holds the code reddit sent, `DELETED_COMMENT` or
+	/// `DELETED_LINK`.
+	Deleted(String),
+	/// The post being replied to is archived (`TOO_OLD`)
+	TooOld,
+	/// The thread is locked (`THREAD_LOCKED`)
+	ThreadLocked,
+	/// The user isn't allowed to do this. Holds the code reddit sent, `USER_REQUIRED` or
+	/// `NOT_AUTHOR`.
+	NotAllowed(String),
+	/// The captcha was wrong or missing (`BAD_CAPTCHA`)
+	BadCaptcha,
+	/// A link was submitted without a url (`NO_URL`)
+	NoUrl,
+	/// The url has already been submitted to the subreddit (`ALREADY_SUB`)
+	AlreadySub,
+	/// A code orca doesn't know about
+	Other(String),
+}
+
+impl ApiErrorCode {
+	/// Gets the error code from the code reddit sent
+	pub fn from_code(code: &str) -> ApiErrorCode {
+		match code {
+			"RATELIMIT" => ApiErrorCode::Ratelimit,
+			"SUBREDDIT_NOEXIST" => ApiErrorCode::SubredditNoexist,
+			"SUBREDDIT_NOTALLOWED" => ApiErrorCode::SubredditNotallowed,
+			"TOO_LONG" => ApiErrorCode::TooLong,
+			"NO_TEXT" => ApiErrorCode::NoText,
+			"NO_SUBJECT" => ApiErrorCode::NoSubject,
+			"USER_DOESNT_EXIST" => ApiErrorCode::UserDoesntExist,
+			"DELETED_COMMENT" | "DELETED_LINK" => ApiErrorCode::Deleted(code.to_string()),
+			"TOO_OLD" => ApiErrorCode::TooOld,
+			"THREAD_LOCKED" => ApiErrorCode::ThreadLocked,
+			"USER_REQUIRED" | "NOT_AUTHOR" => ApiErrorCode::NotAllowed(code.to_string()),
+			"BAD_CAPTCHA" => ApiErrorCode::BadCaptcha,
+			"NO_URL" => ApiErrorCode::NoUrl,
+			"ALREADY_SUB" => ApiErrorCode::AlreadySub,
+			code => ApiErrorCode::Other(code.to_string()),
+		}
+	}
+}
+
+impl fmt::Display for ApiErrorCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let code = match *self {
+			ApiErrorCode::Ratelimit => "RATELIMIT",
+			ApiErrorCode::SubredditNoexist => "SUBREDDIT_NOEXIST",
+			ApiErrorCode::SubredditNotallowed => "SUBREDDIT_NOTALLOWED",
+			ApiErrorCode::TooLong => "TOO_LONG",
+			ApiErrorCode::NoText => "NO_TEXT",
+			ApiErrorCode::NoSubject => "NO_SUBJECT",
+			ApiErrorCode::UserDoesntExist => "USER_DOESNT_EXIST",
+			ApiErrorCode::Deleted(ref code) => code,
+			ApiErrorCode::TooOld => "TOO_OLD",
+			ApiErrorCode::ThreadLocked => "THREAD_LOCKED",
+			ApiErrorCode::NotAllowed(ref code) => code,
+			ApiErrorCode::BadCaptcha => "BAD_CAPTCHA",
+			ApiErrorCode::NoUrl => "NO_URL",
+			ApiErrorCode::AlreadySub => "ALREADY_SUB",
+			ApiErrorCode::Other(ref code) => code,
+		};
+		write!(f, "{}", code)
+	}
 }
 
 /// An error representing a json value that could not be parsed as a certain struct
@@ -13,13 +13,15 @@
 //! * commenting and replying
 //! * comment streams from subreddits
 //! * private messages
-//! * authorization as script or installed oauth app type
+//! * authorization as script, installed, or web oauth app type, or as the app alone
 //! * more stuff
 //!
 //! ## Structure
 //! All of the functionality necessary is available in the implementation of
 //! the `App` struct. Data structures are defined in `orca::data`. Networking code is present in
-//! the net module, which also contains OAuth authorization functionality.
+//! the net module, which also contains OAuth authorization functionality. Requests are sent through
+//! a `Transport`, which can be swapped out for a `MockTransport` to test without a network, or for
+//! a `RecordingTransport` and `ReplayTransport` to record real traffic and replay it later.
 //!
 //! ## Usage
 //! To simply create a reddit app instance, do
@@ -45,7 +47,15 @@
 //! # let (id, secret, username, password) = ("a", "b", "c", "d");
 //! reddit.authorize_script(id, secret, username, password).unwrap();
 //! ```
-//! More info can be found in the documentation for the net module
+//! Accounts with two-factor authentication turned on use `authorize_script_with_totp` instead,
+//! with the two-factor secret of the user. More info can be found in the documentation for the
+//! net module.
+//! The credentials can also be kept in a praw.ini file shared with PRAW, and loaded with `Config`:
+//!
+//! ```rust,no_run
+//! # use orca::Config;
+//! let reddit = Config::load("mybot").unwrap().app().unwrap();
+//! ```
 //!
 //! Actually doing something is simple and similar to previous examples. To get info about the
 //! currently authorized user, simply call
@@ -58,23 +68,47 @@
 //!
 //! which will return a json value until the actual user data structure is implemented.
 //!
+//! ## Async
+//! Every method of `App` blocks until reddit responds. For use on an async runtime, there is also
+//! `AsyncApp`, which has the same methods but returns futures instead. `App` is a thin wrapper that
+//! runs these futures to completion, and can be converted to one with `App::into_async`.
+//!
+//! ```rust,no_run
+//! # extern crate futures;
+//! # extern crate orca;
+//! # use futures::Future;
+//! # use orca::App;
+//! # fn main() {
+//! # let reddit = App::new("a", "b", "c").unwrap().into_async();
+//! let user = reddit.get_user("IntrepidPig").map(|user| println!("{}", user));
+//! # }
+//! ```
+//!
 
+extern crate base32;
 extern crate chrono;
 #[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 extern crate futures;
+extern crate hmac;
 extern crate hyper;
 extern crate hyper_tls;
 #[macro_use]
 extern crate log;
+extern crate native_tls;
 extern crate base64;
 extern crate open;
 extern crate rand;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json as json;
+extern crate sha1;
+extern crate tokio;
 extern crate tokio_core;
+extern crate tokio_timer;
 extern crate url;
 
 #[cfg(test)]
@@ -92,8 +126,18 @@ pub mod errors;
 /// Main entry point
 pub mod app;
 
-pub use app::App;
+/// Loading credentials from praw.ini files
+pub mod config;
+
+pub use app::{App, AppBuilder, AsyncApp, RedditFuture};
+pub use config::Config;
 pub use data::{Sort, SortTime};
-pub use errors::RedditError;
-pub use net::auth::{self, InstalledAppError, ResponseGenFn, Scopes};
-pub use net::{Connection, LimitMethod};
+pub use errors::{ApiErrorCode, RedditError};
+pub use net::auth::{self, InstalledAppError, ResponseGenFn, Scopes, TokenDuration};
+pub use net::cassette::{Cassette, RecordingTransport, ReplayTransport};
+pub use net::proxy::Proxy;
+pub use net::ratelimit::{Budget, RateLimiter, Snapshot, TokenBucket};
+pub use net::store::{FileTokenStore, TokenStore};
+pub use net::totp::Totp;
+pub use net::transport::{HyperConfig, HyperTransport, MockResponse, MockTransport, Transport};
+pub use net::{Connection, Hosts, LimitMethod, RequestTimeout, RetryPolicy};
@@ -1,7 +1,7 @@
 //! # Authorization
 //! Authorization for a Reddit client is done by OAuth, which can be done multiple (3) ways. The
-//! possible methods of authorization are Script, Installed App, and Web App. Currently, only
-//! the first two are supported by orca. There are certain use cases for each app type.
+//! possible methods of authorization are Script, Installed App, and Web App, which are all
+//! supported by orca. There are certain use cases for each app type.
 //!
 //! ## Scripts
 //!
@@ -54,42 +54,95 @@
 //! click allow, the page should redirect to a simple display of the words `Authorization successful`.
 //! Hopefully this too will be customizable one day.
 //!
+//! The redirect server gives up if the user doesn't authorize the app within five minutes.
+//!
+//! On machines without a browser, such as servers reached over SSH, use
+//! `App::begin_installed_app` instead. It returns the url for the user to visit on any device,
+//! without starting a server. After authorizing, the browser is redirected to the redirect uri,
+//! which will usually fail to load; the user then pastes the url from the address bar (or just
+//! the code in it) back into your program, which passes it to `App::complete_installed_app`.
+//!
 //! Installed apps, unlike scripts, require periodic reauthorization, or will expire without the
 //! possibility of refreshing if a permanent duration wasn't requested. This should be done
-//! automatically by the `net::Connection` instance.
+//! automatically by the `net::Connection` instance. A temporary duration keeps refresh tokens off
+//! shared machines; once its token expires, authorized requests fail with
+//! `RedditError::TokenExpired` until the app is authorized again.
+//!
+//! To keep users from going through the browser every time the program starts, give the app a
+//! `TokenStore` such as a `FileTokenStore` with `App::set_token_store` before authorizing. The
+//! authorization is saved whenever the token changes, and `App::authorize_from_store` restores it
+//! the next time. If you'd rather keep just the refresh token yourself, it can be passed to
+//! `App::authorize_with_refresh_token` instead.
+//!
+//! ## Web Apps
+//!
+//! Web apps are used when your app runs on a server and users authorize it through their browser,
+//! like installed apps, but the app can keep a secret. Register the app with the web app type,
+//! and set the redirect uri to the page of your web server that should receive the user after
+//! they authorize your app.
+//!
+//! orca doesn't start a server or open a browser for web apps. Instead, `App::begin_web_app`
+//! returns the url to send the user to. When reddit redirects them back to your server, pass the
+//! `code` and `state` query parameters of the request to `App::complete_web_app`, which checks
+//! the state and retrieves a token that is refreshed automatically.
+//!
+//! ## Application Only
+//!
+//! Apps that only read public data don't need a user, but still benefit from the higher ratelimit
+//! of the authorized API. Application only authorization gets a token for the app itself, either
+//! with the secret of a script or web app (`App::authorize_application`), or with a unique device
+//! id for installed apps (`App::authorize_installed_client`). A new token is retrieved
+//! automatically when the current one expires. Once authorized this way, reading methods like
+//! `get_posts` go through `oauth.reddit.com`, while anything that needs a user will be refused by
+//! reddit.
 
 use rand::{self, Rng};
 use std;
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use base64;
 use failure::Error;
-use futures::future::ok;
+use futures::future::{self, ok};
 use futures::sync::oneshot::{self, Sender};
 use futures::Future;
 use hyper::header::{self, HeaderValue};
 use hyper::server::Server;
 use hyper::service::{MakeService, Service};
 use hyper::{Body, Error as HyperError, Method, Request, Response};
+use json::Value;
 use open;
+use tokio_timer::Delay;
 use url::{self, Url};
 
 use errors::RedditError;
-use net::body_from_map;
+use net::totp::Totp;
+use net::Form;
 use net::Connection;
 
+/// How long the built in redirect server waits for the user to authorize an installed app
+const REDIRECT_TIMEOUT_SECS: u64 = 300;
+
+/// How long before a token expires it is renewed, so requests sent just before the expiry don't
+/// arrive with a stale token
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
 /// Function type that is passed into OAuthApp::InstalledApp to generate response from code retrieval.
 pub type ResponseGenFn = (Fn(&Result<String, InstalledAppError>) -> Response<Body>) + Send + Sync;
 
 type CodeSender = Arc<Mutex<Option<Sender<Result<String, InstalledAppError>>>>>;
 
 /// Enum representing OAuth information that has been aquired from authorization. This should only be
-/// used internally within orca.
-#[derive(Debug, Clone)]
+/// used internally within orca, other than to save and restore it with a `TokenStore`.
+///
+/// Note: a serialized script authorization contains the password of the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum OAuth {
 	/// Script app type
 	Script {
@@ -101,8 +154,18 @@ pub enum OAuth {
 		username: String,
 		/// Password of the script user
 		password: String,
+		/// Base32 secret to generate two-factor codes with, if the user has two-factor
+		/// authentication turned on
+		#[serde(default)]
+		totp_secret: Option<String>,
 		/// Token retrieved from script authorization
 		token: String,
+		/// Instant when the current token expires
+		#[serde(with = "unix_time")]
+		expire_instant: Option<Instant>,
+		/// The scopes reddit granted, if known
+		#[serde(default)]
+		scopes: Option<Scopes>,
 	},
 	/// Installed app type
 	InstalledApp {
@@ -111,54 +174,313 @@ pub enum OAuth {
 		/// Redirect url of the installed app
 		redirect: String,
 		/// Token currently in use
-		token: RefCell<String>,
+		token: String,
+		/// The refresh token (to be used to retrieve a new token once the current one expires).
+		/// Not present if temporary authorization was requested
+		refresh_token: Option<String>,
+		/// Instant when the current token expires
+		#[serde(with = "unix_time")]
+		expire_instant: Option<Instant>,
+		/// The scopes reddit granted, if known
+		#[serde(default)]
+		scopes: Option<Scopes>,
+	},
+	/// Web app type
+	WebApp {
+		/// Id of the web app
+		id: String,
+		/// Secret of the web app
+		secret: String,
+		/// Redirect url of the web app
+		redirect: String,
+		/// Token currently in use
+		token: String,
 		/// The refresh token (to be used to retrieve a new token once the current one expires).
 		/// Not present if temporary authorization was requested
-		refresh_token: RefCell<Option<String>>,
+		refresh_token: Option<String>,
 		/// Instant when the current token expires
-		expire_instant: Cell<Option<Instant>>,
+		#[serde(with = "unix_time")]
+		expire_instant: Option<Instant>,
+		/// The scopes reddit granted, if known
+		#[serde(default)]
+		scopes: Option<Scopes>,
 	},
+	/// Application only authorization, which isn't tied to a user. A new token is retrieved with
+	/// the same grant when the current one expires.
+	Application {
+		/// Id of the app
+		id: String,
+		/// Secret of the app, for the `client_credentials` grant of confidential clients
+		secret: Option<String>,
+		/// Unique id of the device, for the `installed_client` grant of installed apps
+		device_id: Option<String>,
+		/// Token currently in use
+		token: String,
+		/// Instant when the current token expires
+		#[serde(with = "unix_time")]
+		expire_instant: Option<Instant>,
+		/// The scopes reddit granted, if known
+		#[serde(default)]
+		scopes: Option<Scopes>,
+	},
+}
+
+/// How long an installed or web app authorization lasts
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenDuration {
+	/// The token expires after an hour and can't be refreshed, so nothing long lived is kept
+	Temporary,
+	/// The token comes with a refresh token, so it can be refreshed for as long as the user
+	/// doesn't revoke it
+	Permanent,
+}
+
+impl TokenDuration {
+	/// The name reddit uses for the duration
+	fn as_str(&self) -> &'static str {
+		match *self {
+			TokenDuration::Temporary => "temporary",
+			TokenDuration::Permanent => "permanent",
+		}
+	}
+}
+
+/// An authorization that was started by sending the user to reddit, waiting for the code reddit
+/// redirects back with. These are kept by the connection until they're completed.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingAuth {
+	/// Id of the app
+	pub id: String,
+	/// Secret of the app, if it has one
+	pub secret: Option<String>,
+	/// Redirect url of the app
+	pub redirect: String,
+	/// How long the authorization lasts
+	pub duration: TokenDuration,
+	/// When the authorization was started
+	pub started: Instant,
 }
 
 impl OAuth {
-	/// Refreshes the token (only necessary for installed app types)
-	pub fn refresh(&self, conn: &Connection) -> Result<(), Error> {
+	/// Refreshes the token, or authorizes again with the same credentials if the authorization type
+	/// doesn't have refresh tokens. The new token is saved to the token store of the connection.
+	pub fn refresh(&mut self, conn: &Connection) -> Result<(), Error> {
+		if let Some(tokenreq) = self.refresh_request(conn)? {
+			// Send the request and get the access token as a response
+			let response = conn.run_request(tokenreq)?;
+			self.apply_refresh(&response)?;
+			conn.save_auth(self);
+			Ok(())
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Whether the token has expired (or is about to) and can be refreshed
+	pub fn needs_refresh(&self) -> bool {
+		let expired = |expire_instant: Instant| Instant::now() + Duration::new(EXPIRY_MARGIN_SECS, 0) > expire_instant;
 		match *self {
-			OAuth::Script { .. } => Ok(()),
+			OAuth::Script { expire_instant, .. } | OAuth::Application { expire_instant, .. } => expire_instant.is_some_and(expired),
+			OAuth::InstalledApp {
+				ref refresh_token,
+				ref expire_instant,
+				..
+			}
+			| OAuth::WebApp {
+				ref refresh_token,
+				ref expire_instant,
+				..
+			} => match (refresh_token.as_ref(), *expire_instant) {
+				(Some(_), Some(expire_instant)) => expired(expire_instant),
+				_ => false,
+			},
+		}
+	}
+
+	/// Whether a new token can be retrieved without the user, either with a refresh token or by
+	/// authorizing again with the same credentials
+	pub fn can_reauthorize(&self) -> bool {
+		match *self {
+			OAuth::Script { .. } | OAuth::Application { .. } => true,
+			OAuth::InstalledApp { ref refresh_token, .. } | OAuth::WebApp { ref refresh_token, .. } => refresh_token.is_some(),
+		}
+	}
+
+	/// Creates the requests that revoke the tokens of this authorization, the refresh token first
+	/// if there is one since that also revokes the access tokens retrieved with it
+	pub fn revoke_requests(&self, conn: &Connection) -> Vec<Request<Body>> {
+		let (id, secret, token, refresh_token) = match *self {
+			OAuth::Script { ref id, ref secret, ref token, .. } => (id, secret.as_str(), token, None),
 			OAuth::InstalledApp {
 				ref id,
-				redirect: ref _redirect,
 				ref token,
 				ref refresh_token,
-				ref expire_instant,
-			} => {
-				let old_refresh_token = if let Some(ref refresh_token) = *refresh_token.borrow() { refresh_token.clone() } else { return Err(RedditError::AuthError.into()) };
-				// Get the access token with the new code we just got
-				let mut params: HashMap<&str, &str> = HashMap::new();
-				params.insert("grant_type", "refresh_token");
-				params.insert("refresh_token", &old_refresh_token);
-
-				// Request for the access token
-				let mut tokenreq = Request::builder().method(Method::POST).uri("https://www.reddit.com/api/v1/access_token/.json").body(body_from_map(&params)).unwrap();
+				..
+			} => (id, "", token, refresh_token.as_ref()),
+			OAuth::WebApp {
+				ref id,
+				ref secret,
+				ref token,
+				ref refresh_token,
+				..
+			} => (id, secret.as_str(), token, refresh_token.as_ref()),
+			OAuth::Application { ref id, ref secret, ref token, .. } => (id, secret.as_ref().map(|s| s.as_str()).unwrap_or(""), token, None),
+		};
+
+		let mut tokens = Vec::new();
+		if let Some(refresh_token) = refresh_token {
+			tokens.push((refresh_token, "refresh_token"));
+		}
+		tokens.push((token, "access_token"));
+
+		tokens
+			.into_iter()
+			.map(|(token, hint)| {
+				let form = Form::new().param("token", token.as_str()).param("token_type_hint", hint);
+				let mut req = Request::builder().method(Method::POST).uri(conn.token_url("/api/v1/revoke_token")).body(form.into()).unwrap();
 				// httpS is important
-				tokenreq.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", { base64::encode(&format!("{}:", id)) })).unwrap());
+				req.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", base64::encode(&format!("{}:{}", id, secret)))).unwrap());
+				req
+			})
+			.collect()
+	}
+
+	/// When the current token expires, if known
+	pub fn expire_instant(&self) -> Option<Instant> {
+		match *self {
+			OAuth::Script { expire_instant, .. } | OAuth::InstalledApp { expire_instant, .. } | OAuth::WebApp { expire_instant, .. } | OAuth::Application { expire_instant, .. } => expire_instant,
+		}
+	}
+
+	/// The scopes reddit granted, if known
+	pub fn scopes(&self) -> Option<&Scopes> {
+		match *self {
+			OAuth::Script { ref scopes, .. } | OAuth::InstalledApp { ref scopes, .. } | OAuth::WebApp { ref scopes, .. } | OAuth::Application { ref scopes, .. } => scopes.as_ref(),
+		}
+	}
+
+	/// Returns an error if reddit is known not to have granted a scope
+	/// # Arguments
+	/// * `scope` - The name of the scope, such as `submit`
+	pub fn require_scope(&self, scope: &str) -> Result<(), RedditError> {
+		match self.scopes() {
+			Some(scopes) if !scopes.has(scope) => Err(RedditError::MissingScope { scope: scope.to_string() }),
+			_ => Ok(()),
+		}
+	}
+
+	/// The current bearer token, or `None` if it has expired and can't be refreshed
+	pub fn token(&self) -> Option<String> {
+		match *self {
+			OAuth::Script { ref token, .. } | OAuth::Application { ref token, .. } => Some(token.to_string()),
+			OAuth::InstalledApp {
+				ref token,
+				ref refresh_token,
+				ref expire_instant,
+				..
+			}
+			| OAuth::WebApp {
+				ref token,
+				ref refresh_token,
+				ref expire_instant,
+				..
+			} => match (refresh_token.as_ref(), *expire_instant) {
+				(None, Some(expire_instant)) if Instant::now() > expire_instant => None,
+				_ => Some(token.to_string()),
+			},
+		}
+	}
 
-				// Send the request and get the access token as a response
-				let response = conn.run_request(tokenreq)?;
+	/// Creates the request that retrieves a new token, if this authorization type needs one
+	pub fn refresh_request(&self, conn: &Connection) -> Result<Option<Request<Body>>, Error> {
+		let (id, secret, refresh_token) = match *self {
+			OAuth::Script {
+				ref id,
+				ref secret,
+				ref username,
+				ref password,
+				ref totp_secret,
+				..
+			} => return OAuth::script_request(conn, id, secret, username, password, totp_secret.as_ref().map(|s| s.as_str())).map(Some),
+			OAuth::Application {
+				ref id,
+				ref secret,
+				ref device_id,
+				..
+			} => return Ok(Some(OAuth::application_request(conn, id, secret.as_ref().map(|s| s.as_str()), device_id.as_ref().map(|s| s.as_str())))),
+			OAuth::InstalledApp { ref id, ref refresh_token, .. } => (id, "", refresh_token),
+			OAuth::WebApp {
+				ref id,
+				ref secret,
+				ref refresh_token,
+				..
+			} => (id, secret.as_str(), refresh_token),
+		};
+
+		let old_refresh_token = if let Some(ref refresh_token) = *refresh_token { refresh_token.clone() } else { return Err(RedditError::AuthError.into()) };
+		// Get the access token with the new code we just got
+		let form = Form::new().param("grant_type", "refresh_token").param("refresh_token", old_refresh_token);
 
-				if let (Some(expires_in), Some(new_token), Some(scope)) = (response.get("expires_in"), response.get("access_token"), response.get("scope")) {
-					let expires_in = expires_in.as_u64().unwrap();
-					let new_token = new_token.as_str().unwrap();
-					let _scope = scope.as_str().unwrap();
-					*token.borrow_mut() = new_token.to_string();
-					expire_instant.set(Some(Instant::now() + Duration::new(expires_in.to_string().parse::<u64>().unwrap(), 0)));
+		// Request for the access token
+		let mut tokenreq = Request::builder().method(Method::POST).uri(conn.token_url("/api/v1/access_token/.json")).body(form.into()).unwrap();
+		// httpS is important
+		tokenreq.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", { base64::encode(&format!("{}:{}", id, secret)) })).unwrap());
 
-					Ok(())
-				} else {
-					Err(Error::from(RedditError::AuthError))
+		Ok(Some(tokenreq))
+	}
+
+	/// Updates the token, and the refresh token if reddit rotated it, from the response to a
+	/// request created by `refresh_request`
+	pub fn apply_refresh(&mut self, response: &Value) -> Result<(), Error> {
+		match *self {
+			OAuth::Script {
+				ref mut token,
+				ref mut expire_instant,
+				ref mut scopes,
+				..
+			}
+			| OAuth::InstalledApp {
+				ref mut token,
+				ref mut expire_instant,
+				ref mut scopes,
+				..
+			}
+			| OAuth::WebApp {
+				ref mut token,
+				ref mut expire_instant,
+				ref mut scopes,
+				..
+			}
+			| OAuth::Application {
+				ref mut token,
+				ref mut expire_instant,
+				ref mut scopes,
+				..
+			} => {
+				// Malformed responses are an error rather than a panic, since this also runs in the background
+				match (response["expires_in"].as_u64(), response["access_token"].as_str()) {
+					(Some(expires_in), Some(new_token)) => {
+						// Without a scope in the response, the scopes granted before still apply
+						if let Some(scope) = response["scope"].as_str() {
+							*scopes = Some(Scopes::parse_granted(scope));
+						}
+						*token = new_token.to_string();
+						*expire_instant = Some(Instant::now() + Duration::new(expires_in, 0));
+					}
+					_ => return Err(Error::from(RedditError::AuthError)),
 				}
 			}
 		}
+
+		// Reddit can rotate the refresh token, and the old one stops working once it does
+		if let Some(new_refresh_token) = response["refresh_token"].as_str() {
+			match *self {
+				OAuth::InstalledApp { ref mut refresh_token, .. } | OAuth::WebApp { ref mut refresh_token, .. } => *refresh_token = Some(new_refresh_token.to_string()),
+				OAuth::Script { .. } | OAuth::Application { .. } => {}
+			}
+		}
+
+		Ok(())
 	}
 
 	/// Authorize the app as a script
@@ -169,32 +491,268 @@ impl OAuth {
 	/// * `username` - The username of the user to authorize as
 	/// * `password` - The password of the user to authorize as
 	pub fn create_script(conn: &Connection, id: &str, secret: &str, username: &str, password: &str) -> Result<OAuth, Error> {
+		conn.run(OAuth::create_script_async(conn, id, secret, username, password))
+	}
+
+	/// Authorize the app as a script for a user with two-factor authentication turned on
+	/// # Arguments
+	/// * `conn` - A refernce to the connection to authorize
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `username` - The username of the user to authorize as
+	/// * `password` - The password of the user to authorize as
+	/// * `totp_secret` - The base32 two-factor secret of the user
+	pub fn create_script_with_totp(conn: &Connection, id: &str, secret: &str, username: &str, password: &str, totp_secret: &str) -> Result<OAuth, Error> {
+		conn.run(OAuth::create_script_with_totp_async(conn, id, secret, username, password, totp_secret))
+	}
+
+	/// Authorize the app as a script without blocking
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection to authorize or an `Arc` holding it
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `username` - The username of the user to authorize as
+	/// * `password` - The password of the user to authorize as
+	pub fn create_script_async<'a, C>(conn: C, id: &str, secret: &str, username: &str, password: &str) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		OAuth::script_async(conn, id, secret, username, password, None)
+	}
+
+	/// Authorize the app as a script for a user with two-factor authentication turned on, without
+	/// blocking. A new code is generated from the secret whenever the script authorizes.
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection to authorize or an `Arc` holding it
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit
+	/// * `username` - The username of the user to authorize as
+	/// * `password` - The password of the user to authorize as
+	/// * `totp_secret` - The base32 two-factor secret of the user
+	pub fn create_script_with_totp_async<'a, C>(conn: C, id: &str, secret: &str, username: &str, password: &str, totp_secret: &str) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		OAuth::script_async(conn, id, secret, username, password, Some(totp_secret))
+	}
+
+	/// Sends the request for a script token and creates the authorization from the response
+	fn script_async<'a, C>(conn: C, id: &str, secret: &str, username: &str, password: &str, totp_secret: Option<&str>) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		let tokenreq = match OAuth::script_request(&conn, id, secret, username, password, totp_secret) {
+			Ok(tokenreq) => tokenreq,
+			Err(e) => return Box::new(future::err(e)),
+		};
+
+		let (id, secret, username, password) = (id.to_string(), secret.to_string(), username.to_string(), password.to_string());
+		let totp_secret = totp_secret.map(|s| s.to_string());
+
+		// Send the request and get the bearer token as a response
+		Box::new(Connection::send_request(conn, tokenreq).and_then(move |response| {
+			if let Some(token) = response.get("access_token") {
+				let token = token.as_str().unwrap().to_string();
+				let expire_instant = response.get("expires_in").and_then(Value::as_u64).map(|secs| Instant::now() + Duration::new(secs, 0));
+				let scopes = response.get("scope").and_then(Value::as_str).map(Scopes::parse_granted);
+				Ok(OAuth::Script {
+					id,
+					secret,
+					username,
+					password,
+					totp_secret,
+					token,
+					expire_instant,
+					scopes,
+				})
+			} else {
+				Err(RedditError::AuthError.into())
+			}
+		}))
+	}
+
+	/// Creates the request for a script token. Scripts can't refresh their tokens, so this is
+	/// also used to get a new one when the current one expires. With two-factor authentication,
+	/// reddit expects the current code after the password, so a new one is generated every time.
+	fn script_request(conn: &Connection, id: &str, secret: &str, username: &str, password: &str, totp_secret: Option<&str>) -> Result<Request<Body>, Error> {
+		let password = match totp_secret {
+			Some(totp_secret) => format!("{}:{}", password, Totp::from_base32(totp_secret)?.now()),
+			None => password.to_string(),
+		};
+
 		// authorization paramaters to request
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("grant_type", "password");
-		params.insert("username", &username);
-		params.insert("password", &password);
+		let form = Form::new().param("grant_type", "password").param("username", username).param("password", password);
 
 		// Request for the bearer token
-		let mut tokenreq = Request::builder().method(Method::POST).uri("https://ssl.reddit.com/api/v1/access_token/.json").body(body_from_map(&params)).unwrap();
+		let mut tokenreq = Request::builder().method(Method::POST).uri(conn.token_url("/api/v1/access_token/.json")).body(form.into()).unwrap();
 		// httpS is important
 		tokenreq.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", { base64::encode(&format!("{}:{}", id, secret)) })).unwrap());
 
-		// Send the request and get the bearer token as a response
-		let response = conn.run_request(tokenreq)?;
-
-		if let Some(token) = response.get("access_token") {
-			let token = token.as_str().unwrap().to_string();
-			Ok(OAuth::Script {
-				id: id.to_string(),
-				secret: secret.to_string(),
-				username: username.to_string(),
-				password: password.to_string(),
-				token,
+		Ok(tokenreq)
+	}
+
+	/// Authorize the app without a user, so it can use the authorized API and its higher ratelimit
+	/// for anything that doesn't need a user
+	/// # Arguments
+	/// * `conn` - A reference to the connection to authorize
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit, for confidential clients (scripts and
+	///   web apps). Installed apps use a device id instead.
+	/// * `device_id` - A unique id of 20-30 characters for the device, for installed apps. Can be
+	///   `DO_NOT_TRACK_THIS_DEVICE` if the device shouldn't be tracked.
+	pub fn create_application(conn: &Connection, id: &str, secret: Option<&str>, device_id: Option<&str>) -> Result<OAuth, Error> {
+		conn.run(OAuth::create_application_async(conn, id, secret, device_id))
+	}
+
+	/// Authorize the app without a user, without blocking
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection to authorize or an `Arc` holding it
+	/// * `id` - The app id registered on Reddit
+	/// * `secret` - The app secret registered on Reddit, for confidential clients
+	/// * `device_id` - A unique id of 20-30 characters for the device, for installed apps
+	pub fn create_application_async<'a, C>(conn: C, id: &str, secret: Option<&str>, device_id: Option<&str>) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		let tokenreq = OAuth::application_request(&conn, id, secret, device_id);
+		let mut auth = OAuth::Application {
+			id: id.to_string(),
+			secret: secret.map(|s| s.to_string()),
+			device_id: device_id.map(|s| s.to_string()),
+			token: String::new(),
+			expire_instant: None,
+			scopes: None,
+		};
+
+		Box::new(Connection::send_request(conn, tokenreq).and_then(move |response| {
+			auth.apply_refresh(&response)?;
+			Ok(auth)
+		}))
+	}
+
+	/// Creates the request for an application only token. Installed apps identify themselves with
+	/// a device id, while confidential clients use their secret.
+	fn application_request(conn: &Connection, id: &str, secret: Option<&str>, device_id: Option<&str>) -> Request<Body> {
+		let form = match device_id {
+			Some(device_id) => Form::new().param("grant_type", "https://oauth.reddit.com/grants/installed_client").param("device_id", device_id),
+			None => Form::new().param("grant_type", "client_credentials"),
+		};
+
+		let mut tokenreq = Request::builder().method(Method::POST).uri(conn.token_url("/api/v1/access_token/.json")).body(form.into()).unwrap();
+		// httpS is important
+		tokenreq.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", { base64::encode(&format!("{}:{}", id, secret.unwrap_or(""))) })).unwrap());
+
+		tokenreq
+	}
+
+	/// Authorize the app as an installed app with a refresh token saved from an earlier
+	/// authorization, without blocking. A new token is retrieved right away.
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection to authorize or an `Arc` holding it
+	/// * `id` - The app id registered on Reddit
+	/// * `refresh_token` - The refresh token of the earlier authorization
+	pub fn from_refresh_token_async<'a, C>(conn: C, id: &str, refresh_token: &str) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		// The redirect uri is only needed to get the first token
+		let auth = OAuth::InstalledApp {
+			id: id.to_string(),
+			redirect: String::new(),
+			token: String::new(),
+			refresh_token: Some(refresh_token.to_string()),
+			expire_instant: None,
+			scopes: None,
+		};
+
+		OAuth::renew_async(conn, auth)
+	}
+
+	/// Retrieves a new token for an authorization that doesn't have one yet, such as one made from
+	/// a saved refresh token, without blocking
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection to authorize or an `Arc` holding it
+	/// * `auth` - The authorization to get a token for
+	pub(crate) fn renew_async<'a, C>(conn: C, mut auth: OAuth) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		let tokenreq = match auth.refresh_request(&conn) {
+			Ok(Some(tokenreq)) => tokenreq,
+			Ok(None) => return Box::new(future::err(Error::from(RedditError::AuthError))),
+			Err(e) => return Box::new(future::err(e)),
+		};
+		Box::new(Connection::send_request(conn, tokenreq).and_then(move |response| {
+			auth.apply_refresh(&response)?;
+			Ok(auth)
+		}))
+	}
+
+	/// Creates the url to send the user to so they can authorize an installed or web app
+	/// # Arguments
+	/// * `conn` - A reference to the connection to authorize
+	/// * `id` - The app id registered on Reddit
+	/// * `redirect` - The app redirect URI registered on Reddit
+	/// * `state` - A random string that reddit sends back with the code, to identify the authorization
+	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
+	/// * `duration` - Whether the authorization should last beyond the first token
+	pub fn authorize_url(conn: &Connection, id: &str, redirect: &str, state: &str, scopes: &Scopes, duration: TokenDuration) -> String {
+		let params = [("client_id", id), ("response_type", "code"), ("state", state), ("redirect_uri", redirect), ("duration", duration.as_str()), ("scope", &scopes.to_string())];
+		format!("{}/api/v1/authorize?{}", conn.hosts().public, url::form_urlencoded::Serializer::new(String::new()).extend_pairs(params.iter()).finish())
+	}
+
+	/// Exchanges the code reddit redirected back with for a token, without blocking. If the app
+	/// has a secret, a web app authorization is created, otherwise an installed app one is.
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection to authorize or an `Arc` holding it
+	/// * `pending` - The authorization the code was given for
+	/// * `code` - The code reddit redirected back with
+	pub(crate) fn from_code_async<'a, C>(conn: C, pending: PendingAuth, code: &str) -> Box<dyn Future<Item = OAuth, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		let form = Form::new().param("grant_type", "authorization_code").param("code", code).param("redirect_uri", pending.redirect.as_str());
+
+		// Request for the access token
+		let mut tokenreq = Request::builder().method(Method::POST).uri(conn.token_url("/api/v1/access_token/.json")).body(form.into()).unwrap();
+		// httpS is important
+		let credentials = format!("{}:{}", pending.id, pending.secret.as_deref().unwrap_or(""));
+		tokenreq.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", base64::encode(&credentials))).unwrap());
+
+		Box::new(Connection::send_request(conn, tokenreq).and_then(move |response| {
+			let token = match response.get("access_token").and_then(Value::as_str) {
+				Some(token) => token.to_string(),
+				None => return Err(Error::from(RedditError::AuthError)),
+			};
+			// A temporary authorization shouldn't leave anything long lived behind
+			let refresh_token = match pending.duration {
+				TokenDuration::Permanent => response.get("refresh_token").and_then(Value::as_str).map(|token| token.to_string()),
+				TokenDuration::Temporary => None,
+			};
+			let expire_instant = response.get("expires_in").and_then(Value::as_u64).map(|secs| Instant::now() + Duration::new(secs, 0));
+			let scopes = response.get("scope").and_then(Value::as_str).map(Scopes::parse_granted);
+
+			let PendingAuth { id, secret, redirect, .. } = pending;
+			Ok(match secret {
+				Some(secret) => OAuth::WebApp {
+					id,
+					secret,
+					redirect,
+					token,
+					refresh_token,
+					expire_instant,
+					scopes,
+				},
+				None => OAuth::InstalledApp {
+					id,
+					redirect,
+					token,
+					refresh_token,
+					expire_instant,
+					scopes,
+				},
 			})
-		} else {
-			Err(RedditError::AuthError.into())
-		}
+		}))
 	}
 
 	/// Authorize the app as an installed app
@@ -203,29 +761,29 @@ impl OAuth {
 	/// * `id` - The app id registered on Reddit
 	/// * `redirect` - The app redirect URI registered on Reddit
 	/// * `response_gen` - An optional function that generates a hyper Response to give to the user
-	/// based on the result of the authorization attempt. The signature is `(Result<String, InstalledAppError) -> Result<Response, Response>`.
-	/// The result passed in is either Ok with the code recieved, or Err with the error that occurred.
-	/// The value returned should usually be an Ok(Response), but you can return Err(Response) to indicate
-	/// that an error occurred within the function.
+	///   based on the result of the authorization attempt. The signature is `(Result<String, InstalledAppError) -> Result<Response, Response>`.
+	///   The result passed in is either Ok with the code recieved, or Err with the error that occurred.
+	///   The value returned should usually be an Ok(Response), but you can return Err(Response) to indicate
+	///   that an error occurred within the function.
 	/// * `scopes` - A reference to a Scopes instance representing the capabilites you are requesting
-	/// as an installed app.
-	pub fn create_installed_app<I: Into<Option<Arc<ResponseGenFn>>>>(conn: &Connection, id: &str, redirect: &str, response_gen: I, scopes: &Scopes) -> Result<OAuth, Error> {
+	///   as an installed app.
+	/// * `duration` - Whether the authorization should last beyond the first token. Temporary
+	///   authorizations expire after an hour, after which the app has to be authorized again.
+	pub fn create_installed_app<I: Into<Option<Arc<ResponseGenFn>>>>(conn: &Connection, id: &str, redirect: &str, response_gen: I, scopes: &Scopes, duration: TokenDuration) -> Result<OAuth, Error> {
 		let response_gen = response_gen.into();
 		// Random state string to identify this authorization instance
 		let state = rand::thread_rng().gen_ascii_chars().take(16).collect::<String>();
 
-		let scopes = &scopes.to_string();
-		let browser_uri = format!(
-			"https://www.reddit.com/api/v1/authorize?client_id={}&response_type=code&\
-			 state={}&redirect_uri={}&duration=permanent&scope={}",
-			id, state, redirect, scopes
-		);
+		let browser_uri = OAuth::authorize_url(conn, id, redirect, &state, scopes, duration);
 
 		let state_rc = Arc::new(state);
 
-		// Open the auth url in the browser so the user can authenticate the app
+		// Open the auth url in the browser so the user can authenticate the app. Without a browser
+		// they can still open the url somewhere else, as long as it redirects here.
 		thread::spawn(move || {
-			open::that(browser_uri).expect("Failed to open browser");
+			if let Err(e) = open::that(&browser_uri) {
+				error!("Failed to open browser ({}), visit {} to authorize the app", e, browser_uri);
+			}
 		});
 
 		// A oneshot future channel that the hyper server has access to to send the code back
@@ -279,6 +837,14 @@ impl OAuth {
 			}
 		});
 
+		// Give up if the user doesn't authorize the app in time
+		let code_clone = Arc::clone(&code);
+		let timeout = Delay::new(Instant::now() + Duration::new(REDIRECT_TIMEOUT_SECS, 0)).then(move |_| {
+			*code_clone.lock().unwrap() = Err(InstalledAppError::TimedOut);
+			Err(())
+		});
+		let finish = finish.select(timeout).then(|_| Ok::<(), ()>(()));
+
 		let graceful = server.with_graceful_shutdown(finish).map_err(|e| eprintln!("Server failed: {}", e));
 
 		// Run the server until the code future oneshot resolves and has set the code variable.
@@ -291,34 +857,65 @@ impl OAuth {
 		};
 
 		// Get the access token with the new code we just got
-		let mut params: HashMap<&str, &str> = HashMap::new();
-		params.insert("grant_type", "authorization_code");
-		params.insert("code", &code);
-		params.insert("redirect_uri", &redirect);
+		let pending = PendingAuth {
+			id: id.to_string(),
+			secret: None,
+			redirect: redirect.to_string(),
+			duration,
+			started: Instant::now(),
+		};
+		match conn.run(OAuth::from_code_async(conn, pending, &code))? {
+			// If a permanent authorization was requested, there should be a refresh token
+			OAuth::InstalledApp { refresh_token: None, .. } if duration == TokenDuration::Permanent => Err(Error::from(RedditError::AuthError)),
+			auth => Ok(auth),
+		}
+	}
 
-		// Request for the access token
-		let mut tokenreq = Request::builder().method(Method::POST).uri("https://ssl.reddit.com/api/v1/access_token/.json").body(body_from_map(&params)).unwrap();
-		// httpS is important
-		tokenreq.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", base64::encode(&format!("{}:", id)))).unwrap());
-
-		// Send the request and get the access token as a response
-		let response = conn.run_request(tokenreq)?;
-
-		if let (Some(expires_in), Some(token), Some(refresh_token), Some(scope)) = (response.get("expires_in"), response.get("access_token"), response.get("refresh_token"), response.get("scope")) {
-			let expires_in = expires_in.as_u64().unwrap();
-			let token = token.as_str().unwrap();
-			let refresh_token = refresh_token.as_str().unwrap();
-			let _scope = scope.as_str().unwrap();
-			Ok(OAuth::InstalledApp {
-				id: id.to_string(),
-				redirect: redirect.to_string(),
-				token: RefCell::new(token.to_string()),
-				refresh_token: RefCell::new(Some(refresh_token.to_string())),
-				expire_instant: Cell::new(Some(Instant::now() + Duration::new(expires_in.to_string().parse::<u64>().unwrap(), 0))),
-			})
-		} else {
-			Err(Error::from(RedditError::AuthError))
+	/// Reads the code and state out of what the user pasted after authorizing an installed app
+	/// without the built in redirect server. This is either the whole url they were redirected to,
+	/// or just the code in it, in which case there is no state.
+	/// # Arguments
+	/// * `pasted` - The redirect url or code
+	pub(crate) fn parse_redirect(pasted: &str) -> Result<(String, Option<String>), InstalledAppError> {
+		let pasted = pasted.trim();
+		if !pasted.contains('?') && !pasted.contains('=') {
+			return if pasted.is_empty() { Err(InstalledAppError::NeverRecieved) } else { Ok((pasted.to_string(), None)) };
 		}
+
+		// Only the query matters, so anything before it doesn't have to be a full url
+		let query = pasted.splitn(2, '?').last().unwrap_or("");
+		let params: HashMap<_, _> = url::form_urlencoded::parse(query.as_bytes()).collect();
+		if let Some(error) = params.get("error") {
+			return Err(InstalledAppError::Error { msg: error.to_string() });
+		}
+		match (params.get("code"), params.get("state")) {
+			(Some(code), Some(state)) => Ok((code.to_string(), Some(state.to_string()))),
+			(Some(_), None) => Err(InstalledAppError::MismatchedState),
+			(None, _) => Err(InstalledAppError::NeverRecieved),
+		}
+	}
+}
+
+/// Serializes token expiry instants as the unix time they happen at, since instants only mean
+/// something within the process that created them
+mod unix_time {
+	use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+	pub fn serialize<S: Serializer>(instant: &Option<Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+		let (now, system_now) = (Instant::now(), SystemTime::now());
+		let time = instant.map(|instant| if instant > now { system_now + (instant - now) } else { system_now - (now - instant) });
+		time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_secs()).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Instant>, D::Error> {
+		let (now, system_now) = (Instant::now(), SystemTime::now());
+		Ok(Option::<u64>::deserialize(deserializer)?.map(|secs| match (UNIX_EPOCH + Duration::new(secs, 0)).duration_since(system_now) {
+			Ok(remaining) => now + remaining,
+			// Tokens that expired in the past are simply expired now
+			Err(_) => now,
+		}))
 	}
 }
 
@@ -326,6 +923,7 @@ impl OAuth {
 /// To use, create an instance of the struct and set the fields you want to use to true.
 ///
 /// Note: In the field documentation, "the user" refers to the currently authorized user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Scopes {
 	/// See detailed info about the user
 	pub identity: bool,
@@ -422,71 +1020,145 @@ impl Scopes {
 		}
 	}
 
-	/// Convert the struct to a string representation to be sent to Reddit
-	fn to_string(&self) -> String {
-		let mut string = String::new();
-		if self.identity {
-			string.push_str("identity");
-		}
-		if self.edit {
-			string.push_str(",edit");
-		}
-		if self.flair {
-			string.push_str(",flair");
-		}
-		if self.history {
-			string.push_str(",history");
-		}
-		if self.modconfig {
-			string.push_str(",modconfig");
-		}
-		if self.modflair {
-			string.push_str(",modflair");
-		}
-		if self.modlog {
-			string.push_str(",modlog");
-		}
-		if self.modposts {
-			string.push_str(",modposts");
-		}
-		if self.modwiki {
-			string.push_str(",modwiki");
-		}
-		if self.mysubreddits {
-			string.push_str(",mysubreddits");
-		}
-		if self.privatemessages {
-			string.push_str(",privatemessages");
-		}
-		if self.read {
-			string.push_str(",read");
-		}
-		if self.report {
-			string.push_str(",report");
-		}
-		if self.save {
-			string.push_str(",save");
-		}
-		if self.submit {
-			string.push_str(",submit");
-		}
-		if self.subscribe {
-			string.push_str(",subscribe");
-		}
-		if self.vote {
-			string.push_str(",vote");
-		}
-		if self.wikiedit {
-			string.push_str(",wikiedit");
-		}
-		if self.wikiread {
-			string.push_str(",wikiread");
+	/// Returns whether a scope is included, by the name reddit uses for it. Unknown names are
+	/// never included.
+	/// # Arguments
+	/// * `name` - The name of the scope, such as `submit`
+	pub fn has(&self, name: &str) -> bool {
+		self.flags().iter().any(|&(flag, included)| flag == name && included)
+	}
+
+	/// Includes or excludes a scope by the name reddit uses for it. Returns whether the name is
+	/// known.
+	/// # Arguments
+	/// * `name` - The name of the scope, such as `submit`
+	/// * `included` - Whether the scope should be included
+	pub fn set(&mut self, name: &str, included: bool) -> bool {
+		let flag = match name {
+			"identity" => &mut self.identity,
+			"edit" => &mut self.edit,
+			"flair" => &mut self.flair,
+			"history" => &mut self.history,
+			"modconfig" => &mut self.modconfig,
+			"modflair" => &mut self.modflair,
+			"modlog" => &mut self.modlog,
+			"modposts" => &mut self.modposts,
+			"modwiki" => &mut self.modwiki,
+			"mysubreddits" => &mut self.mysubreddits,
+			"privatemessages" => &mut self.privatemessages,
+			"read" => &mut self.read,
+			"report" => &mut self.report,
+			"save" => &mut self.save,
+			"submit" => &mut self.submit,
+			"subscribe" => &mut self.subscribe,
+			"vote" => &mut self.vote,
+			"wikiedit" => &mut self.wikiedit,
+			"wikiread" => &mut self.wikiread,
+			"account" => &mut self.account,
+			_ => return false,
+		};
+		*flag = included;
+		true
+	}
+
+	/// Returns whether no scopes are included
+	pub fn is_empty(&self) -> bool {
+		self.flags().iter().all(|&(_, included)| !included)
+	}
+
+	/// Returns whether every scope included in `other` is included in this one too
+	pub fn contains(&self, other: &Scopes) -> bool {
+		self.flags().iter().zip(other.flags().iter()).all(|(&(_, mine), &(_, theirs))| mine || !theirs)
+	}
+
+	/// Returns the scopes included in either this or `other`
+	pub fn union(&self, other: &Scopes) -> Scopes {
+		self.combine(other, |mine, theirs| mine || theirs)
+	}
+
+	/// Returns the scopes included in both this and `other`
+	pub fn intersection(&self, other: &Scopes) -> Scopes {
+		self.combine(other, |mine, theirs| mine && theirs)
+	}
+
+	/// Returns the scopes included in this but not in `other`
+	pub fn difference(&self, other: &Scopes) -> Scopes {
+		self.combine(other, |mine, theirs| mine && !theirs)
+	}
+
+	/// Parses the scopes reddit says it granted. Reddit separates them with spaces, and may grant
+	/// scopes orca doesn't know about yet, which are ignored.
+	pub(crate) fn parse_granted(scopes: &str) -> Scopes {
+		let mut granted = Scopes::empty();
+		for name in scopes.split(|c: char| c == ',' || c.is_whitespace()).filter(|name| !name.is_empty()) {
+			if name == "*" {
+				granted = Scopes::all();
+			} else if !granted.set(name, true) {
+				debug!("Ignoring unknown scope {} granted by reddit", name);
+			}
 		}
-		if self.account {
-			string.push_str(",account");
+		granted
+	}
+
+	/// Every scope by the name reddit uses for it, and whether it's included
+	fn flags(&self) -> [(&'static str, bool); 20] {
+		[
+			("identity", self.identity),
+			("edit", self.edit),
+			("flair", self.flair),
+			("history", self.history),
+			("modconfig", self.modconfig),
+			("modflair", self.modflair),
+			("modlog", self.modlog),
+			("modposts", self.modposts),
+			("modwiki", self.modwiki),
+			("mysubreddits", self.mysubreddits),
+			("privatemessages", self.privatemessages),
+			("read", self.read),
+			("report", self.report),
+			("save", self.save),
+			("submit", self.submit),
+			("subscribe", self.subscribe),
+			("vote", self.vote),
+			("wikiedit", self.wikiedit),
+			("wikiread", self.wikiread),
+			("account", self.account),
+		]
+	}
+
+	/// Creates scopes by combining whether each scope is included in this and `other`
+	fn combine<F: Fn(bool, bool) -> bool>(&self, other: &Scopes, f: F) -> Scopes {
+		let mut combined = Scopes::empty();
+		for (&(name, mine), &(_, theirs)) in self.flags().iter().zip(other.flags().iter()) {
+			combined.set(name, f(mine, theirs));
 		}
+		combined
+	}
+}
+
+/// Formats the scopes as reddit expects them in the authorization url, separated by commas
+impl fmt::Display for Scopes {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let names: Vec<&str> = self.flags().iter().filter(|&&(_, included)| included).map(|&(name, _)| name).collect();
+		write!(f, "{}", names.join(","))
+	}
+}
+
+/// Parses scopes separated by commas or spaces, such as `identity,read submit`. `*` stands for
+/// every scope.
+impl FromStr for Scopes {
+	type Err = Error;
 
-		string
+	fn from_str(s: &str) -> Result<Scopes, Error> {
+		let mut scopes = Scopes::empty();
+		for name in s.split(|c: char| c == ',' || c.is_whitespace()).filter(|name| !name.is_empty()) {
+			if name == "*" {
+				scopes = Scopes::all();
+			} else if !scopes.set(name, true) {
+				return Err(format_err!("Unknown scope {}", name));
+			}
+		}
+		Ok(scopes)
 	}
 }
 
@@ -508,6 +1180,9 @@ pub enum InstalledAppError {
 	/// No message was ever recieved
 	#[fail(display = "No message was ever recieved")]
 	NeverRecieved,
+	/// The user didn't authorize the app before the redirect server stopped waiting
+	#[fail(display = "Timed out waiting for the user to authorize the app")]
+	TimedOut,
 }
 
 struct MakeInstalledAppService {
@@ -0,0 +1,272 @@
+//! # Cassettes
+//! A cassette is a json file of requests sent to reddit and the responses that came back. A
+//! `RecordingTransport` wraps another transport and writes everything that goes through it to a
+//! cassette, and a `ReplayTransport` serves the responses from a cassette without touching the
+//! network. Together they make it possible to test against real reddit traffic deterministically.
+//!
+//! Passwords, secrets and tokens are redacted from the form bodies of requests and from the json
+//! bodies of responses before they're written, so cassettes can be checked in.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use futures::{future, Future, Stream};
+use hyper::{Body, Request, Response, StatusCode};
+use json::{self, Value};
+use url::{form_urlencoded, Position, Url};
+
+use net::transport::{Transport, TransportFuture};
+use net::BufferedRequest;
+
+/// What redacted values are replaced with
+const REDACTED: &str = "REDACTED";
+
+/// Form parameters and json fields whose values are never written to a cassette
+const SECRETS: &[&str] = &["password", "client_secret", "refresh_token", "access_token", "code", "token", "otp"];
+
+/// A recording of requests and their responses
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+	/// The requests and responses in the order they were sent
+	pub interactions: Vec<Interaction>,
+}
+
+/// A request and the response it got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+	/// The request that was sent
+	pub request: RecordedRequest,
+	/// The response that came back
+	pub response: RecordedResponse,
+}
+
+/// A request as it's stored in a cassette
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+	/// The HTTP method of the request
+	pub method: String,
+	/// The url the request was sent to
+	pub uri: String,
+	/// The form body of the request, with secrets redacted
+	pub body: String,
+}
+
+/// A response as it's stored in a cassette
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+	/// The HTTP status code of the response
+	pub status: u16,
+	/// The headers of the response
+	pub headers: BTreeMap<String, String>,
+	/// The body of the response, with secrets redacted if it's json
+	pub body: String,
+}
+
+impl Cassette {
+	/// Reads a cassette from a json file
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Cassette, Error> {
+		let mut contents = String::new();
+		File::open(path)?.read_to_string(&mut contents)?;
+		Ok(json::from_str(&contents)?)
+	}
+
+	/// Writes the cassette to a json file, replacing it if it exists
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let mut file = File::create(path)?;
+		file.write_all(json::to_string_pretty(self)?.as_bytes())?;
+		Ok(())
+	}
+}
+
+/// A transport that sends requests through another transport, and records them and their
+/// responses to a cassette file. The file is rewritten after every response, so nothing is lost
+/// if the program stops early.
+pub struct RecordingTransport {
+	inner: Arc<dyn Transport>,
+	recorder: Arc<Recorder>,
+}
+
+struct Recorder {
+	path: PathBuf,
+	cassette: Mutex<Cassette>,
+}
+
+impl RecordingTransport {
+	/// Creates a transport that records to a new cassette
+	/// # Arguments
+	/// * `inner` - The transport that actually sends the requests, usually a `HyperTransport`
+	/// * `path` - The file to write the cassette to
+	pub fn new<P: Into<PathBuf>>(inner: Arc<dyn Transport>, path: P) -> RecordingTransport {
+		RecordingTransport {
+			inner,
+			recorder: Arc::new(Recorder {
+				path: path.into(),
+				cassette: Mutex::new(Cassette::default()),
+			}),
+		}
+	}
+
+	/// Returns a copy of everything recorded so far
+	pub fn cassette(&self) -> Cassette {
+		self.recorder.cassette.lock().unwrap().clone()
+	}
+}
+
+impl Transport for RecordingTransport {
+	fn request(&self, req: Request<Body>) -> TransportFuture {
+		let inner = Arc::clone(&self.inner);
+		let recorder = Arc::clone(&self.recorder);
+
+		// Both bodies have to be read to be recorded, and are then put back
+		Box::new(BufferedRequest::buffer(req).and_then(move |req| {
+			let request = RecordedRequest {
+				method: req.method.to_string(),
+				uri: req.uri.to_string(),
+				body: redact_form(&String::from_utf8_lossy(&req.body)),
+			};
+
+			inner.request(req.to_request()).and_then(move |response| {
+				let (parts, body) = response.into_parts();
+				body.concat2().map_err(Error::from).map(move |body| {
+					let response = RecordedResponse {
+						status: parts.status.as_u16(),
+						headers: parts.headers.iter().filter_map(|(name, value)| value.to_str().ok().map(|value| (name.as_str().to_string(), value.to_string()))).collect(),
+						body: redact_json(&String::from_utf8_lossy(&body)),
+					};
+					recorder.record(Interaction { request, response });
+
+					Response::from_parts(parts, Body::from(body))
+				})
+			})
+		}))
+	}
+}
+
+impl Recorder {
+	/// Adds an interaction to the cassette and saves it
+	fn record(&self, interaction: Interaction) {
+		let mut cassette = self.cassette.lock().unwrap();
+		cassette.interactions.push(interaction);
+		if let Err(e) = cassette.save(&self.path) {
+			error!("Failed to write cassette {}: {}", self.path.display(), e);
+		}
+	}
+}
+
+/// A transport that serves responses from a cassette instead of sending requests. Each request is
+/// answered with the first response in the cassette recorded for the same method and url that
+/// hasn't been served yet. The order of query parameters doesn't matter.
+pub struct ReplayTransport {
+	remaining: Mutex<Vec<Interaction>>,
+}
+
+impl ReplayTransport {
+	/// Creates a transport that replays a cassette
+	pub fn new(cassette: Cassette) -> ReplayTransport {
+		ReplayTransport {
+			remaining: Mutex::new(cassette.interactions),
+		}
+	}
+
+	/// Creates a transport that replays a cassette file
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ReplayTransport, Error> {
+		Ok(ReplayTransport::new(Cassette::load(path)?))
+	}
+
+	/// Returns the amount of recorded responses that haven't been served yet
+	pub fn remaining(&self) -> usize {
+		self.remaining.lock().unwrap().len()
+	}
+}
+
+impl Transport for ReplayTransport {
+	fn request(&self, req: Request<Body>) -> TransportFuture {
+		let (method, uri) = (req.method().to_string(), req.uri().to_string());
+
+		let interaction = {
+			let mut remaining = self.remaining.lock().unwrap();
+			match remaining.iter().position(|interaction| interaction.request.method == method && same_uri(&interaction.request.uri, &uri)) {
+				Some(i) => remaining.remove(i),
+				None => return Box::new(future::err(format_err!("Cassette has no response recorded for {} {}", method, uri))),
+			}
+		};
+
+		trace!("Replaying response to {} {}", method, uri);
+		let mut builder = Response::builder();
+		builder.status(StatusCode::from_u16(interaction.response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+		for (name, value) in &interaction.response.headers {
+			builder.header(name.as_str(), value.as_str());
+		}
+
+		Box::new(future::result(builder.body(Body::from(interaction.response.body)).map_err(Error::from)))
+	}
+}
+
+/// Replaces the values of secret parameters in a urlencoded form
+fn redact_form(form: &str) -> String {
+	form.split('&')
+		.map(|pair| {
+			let mut parts = pair.splitn(2, '=');
+			let key = parts.next().unwrap_or("");
+			match parts.next() {
+				Some(_) if is_secret(key) => format!("{}={}", key, REDACTED),
+				_ => pair.to_string(),
+			}
+		})
+		.collect::<Vec<String>>()
+		.join("&")
+}
+
+/// Replaces the values of secret fields in a json document. Anything that isn't json is left as is.
+fn redact_json(body: &str) -> String {
+	fn redact(value: &mut Value) {
+		match *value {
+			Value::Object(ref mut map) => {
+				for (key, value) in map.iter_mut() {
+					if is_secret(key) && value.is_string() {
+						*value = Value::String(REDACTED.to_string());
+					} else {
+						redact(value);
+					}
+				}
+			}
+			Value::Array(ref mut values) => {
+				for value in values {
+					redact(value);
+				}
+			}
+			_ => {}
+		}
+	}
+
+	match json::from_str::<Value>(body) {
+		Ok(mut value) => {
+			redact(&mut value);
+			value.to_string()
+		}
+		Err(_) => body.to_string(),
+	}
+}
+
+/// Whether two urls are the same apart from the order of their query parameters
+fn same_uri(a: &str, b: &str) -> bool {
+	fn query(url: &Url) -> Vec<(String, String)> {
+		let mut pairs = url.query_pairs().into_owned().collect::<Vec<(String, String)>>();
+		pairs.sort();
+		pairs
+	}
+
+	match (Url::parse(a), Url::parse(b)) {
+		(Ok(a), Ok(b)) => a[..Position::AfterPath] == b[..Position::AfterPath] && query(&a) == query(&b),
+		_ => a == b,
+	}
+}
+
+fn is_secret(key: &str) -> bool {
+	let key = form_urlencoded::parse(key.as_bytes()).next().map(|(key, _)| key.into_owned()).unwrap_or_else(|| key.to_string());
+	SECRETS.contains(&key.as_str())
+}
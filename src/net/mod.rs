@@ -6,58 +6,234 @@
 
 /// Contains all functionality for OAuth and logins
 pub mod auth;
+/// Recording requests to cassettes and replaying them
+pub mod cassette;
+/// Sending requests through a proxy
+pub mod proxy;
+/// Ratelimiters that can be shared between connections
+pub mod ratelimit;
+/// Keeping authorizations between runs
+pub mod store;
+/// Two-factor codes for scripts
+pub mod totp;
+/// Pluggable HTTP transports, including an in-memory mock
+pub mod transport;
 
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
 use std::hash::BuildHasher;
-use std::thread;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
-use futures::Stream;
-use hyper::client::{Client, HttpConnector};
-use hyper::header::{self, HeaderValue};
-use hyper::{Body, Request, Response, Uri};
-use hyper_tls::HttpsConnector;
+use futures::future::{Loop, Shared, SharedError, SharedItem};
+use futures::{future, Future, Stream};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri, Version};
 use json;
 use json::Value;
-use tokio_core::reactor::Core;
+use rand::{self, Rng};
+use url::form_urlencoded;
+use tokio::runtime::current_thread::Runtime;
+use tokio_timer::{Delay, Timeout};
 
-use self::auth::OAuth;
+use self::auth::{OAuth, PendingAuth};
+pub use self::ratelimit::LimitMethod;
+use self::ratelimit::{Budget, RateLimiter, TokenBucket};
+use self::store::TokenStore;
+use self::transport::{HyperTransport, Transport, TransportFuture};
 use errors::RedditError;
 
 use failure::Error;
 
-/// How to ratelimit
-#[derive(Copy, Clone)]
-pub enum LimitMethod {
-	/// Wait an even amount of time between each request
-	Steady,
-	/// Fire off requests as they come. It's possible there will be a long waiting time for the
-	/// next ratelimit period if too many are fired off at once.
-	Burst,
+/// The hosts that requests are sent to. By default these are reddit's, but they can be changed to
+/// point orca at a local stand-in for reddit or at a proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hosts {
+	/// Host for requests that require authorization (`https://oauth.reddit.com`)
+	pub oauth: String,
+	/// Host for requests that don't require authorization (`https://www.reddit.com`)
+	pub public: String,
+	/// Host of the OAuth token endpoint (`https://ssl.reddit.com`)
+	pub token: String,
+}
+
+impl Hosts {
+	/// Creates a set of hosts. Trailing slashes are removed.
+	/// # Arguments
+	/// * `oauth` - Host for requests that require authorization
+	/// * `public` - Host for requests that don't require authorization
+	/// * `token` - Host of the OAuth token endpoint
+	pub fn new(oauth: &str, public: &str, token: &str) -> Hosts {
+		fn trim(host: &str) -> String {
+			let mut host = host.to_string();
+			while host.ends_with('/') {
+				host.pop();
+			}
+			host
+		}
+
+		Hosts {
+			oauth: trim(oauth),
+			public: trim(public),
+			token: trim(token),
+		}
+	}
+
+	/// Creates a set of hosts that all point to the same host, such as a mock server
+	pub fn single(host: &str) -> Hosts {
+		Hosts::new(host, host, host)
+	}
+}
+
+impl Default for Hosts {
+	fn default() -> Hosts {
+		Hosts::new("https://oauth.reddit.com", "https://www.reddit.com", "https://ssl.reddit.com")
+	}
+}
+
+/// How long requests wait for a response by default
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// How long an authorization that was started waits for its code before it's forgotten
+const PENDING_AUTH_TTL_SECS: u64 = 10 * 60;
+
+/// The most authorizations waiting for a code that are kept at once
+const MAX_PENDING_AUTHS: usize = 32;
+
+thread_local! {
+	/// The runtime `Connection::run` blocks on. The futures it's given borrow the connection and
+	/// aren't `Send`, so they can't be handed to a shared runtime, but a runtime for each thread
+	/// still saves making a new one for every request.
+	static BLOCKING_RUNTIME: RefCell<Option<Runtime>> = const { RefCell::new(None) };
+}
+
+/// Forgets the authorizations that have waited too long for their code
+fn prune_pending(pending: &mut HashMap<String, PendingAuth>) {
+	let ttl = Duration::from_secs(PENDING_AUTH_TTL_SECS);
+	pending.retain(|_, auth| auth.started.elapsed() < ttl);
+}
+
+/// Overrides the timeout of the connection for a single request when it's added to the
+/// extensions of the request. `None` lets the request wait as long as it takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTimeout(pub Option<Duration>);
+
+/// When and how to retry requests that failed for reasons that are likely temporary, like a
+/// dropped connection or reddit being overloaded (HTTP 429, 500, 502, 503 and 504).
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+	/// Most times a request is sent, including the first attempt
+	pub max_attempts: u32,
+	/// Delay before the first retry, which doubles for every retry after it
+	pub base_delay: Duration,
+	/// Longest delay between attempts, unless reddit asks for a longer one
+	pub max_delay: Duration,
+	/// Whether requests that aren't idempotent, like submitting a post, are retried too. Reddit
+	/// might have acted on a write even though the response was an error, so this is off by default.
+	pub retry_writes: bool,
+}
+
+impl RetryPolicy {
+	/// A policy that never retries requests
+	pub fn never() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 1,
+			..RetryPolicy::default()
+		}
+	}
+
+	/// Whether requests with this method should be retried
+	fn retries(&self, method: &Method) -> bool {
+		self.max_attempts > 1 && (self.retry_writes || method.is_idempotent())
+	}
+
+	/// How long to wait before sending a request again after the attempt number `attempt` got
+	/// the response `response`, or `None` if the response shouldn't be retried. Reddit's
+	/// `Retry-After` and `x-ratelimit-reset` headers are used as the delay if they're present.
+	fn delay_for(&self, attempt: u32, response: &Response<Body>) -> Option<Duration> {
+		let status = response.status();
+		if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::INTERNAL_SERVER_ERROR && status != StatusCode::BAD_GATEWAY && status != StatusCode::SERVICE_UNAVAILABLE && status != StatusCode::GATEWAY_TIMEOUT {
+			return None;
+		}
+
+		let header_secs = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).and_then(|value| value.trim().parse::<f32>().ok());
+		let requested = match header_secs("retry-after") {
+			Some(secs) => Some(secs),
+			None if status == StatusCode::TOO_MANY_REQUESTS => header_secs("x-ratelimit-reset"),
+			None => None,
+		};
+
+		match requested {
+			Some(secs) if secs >= 0.0 => Some(Duration::from_millis((secs * 1000.0) as u64)),
+			_ => Some(self.backoff(attempt)),
+		}
+	}
+
+	/// Exponential backoff with jitter. The delay doubles with every attempt until it reaches
+	/// `max_delay`, and then a random amount of up to half of it is taken off.
+	fn backoff(&self, attempt: u32) -> Duration {
+		fn millis(duration: Duration) -> u64 {
+			duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+		}
+
+		let exponent = cmp::min(attempt.saturating_sub(1), 16);
+		let delay = cmp::min(millis(self.base_delay).saturating_mul(1 << exponent), millis(self.max_delay));
+		let jitter = if delay / 2 > 0 { rand::thread_rng().gen_range(0, delay / 2) } else { 0 };
+
+		Duration::from_millis(delay - delay / 2 + jitter)
+	}
+}
+
+impl Default for RetryPolicy {
+	/// Retries idempotent requests up to 3 times, starting with a one second delay
+	fn default() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 4,
+			base_delay: Duration::from_secs(1),
+			max_delay: Duration::from_secs(60),
+			retry_writes: false,
+		}
+	}
 }
 
 /// A connection holder to reddit. Holds authorization info if provided, and is in charge
 /// of ratelimiting.
+///
+/// A connection can be shared between threads. Requests sent from any of them share the same
+/// ratelimit and authorization, and an expired token is only refreshed once.
 pub struct Connection {
 	/// Authorization info (optional, but required for sending authorized requests)
-	pub auth: Option<auth::OAuth>,
+	pub auth: RwLock<Option<auth::OAuth>>,
 	/// User agent for the client
 	pub useragent: HeaderValue,
-	/// HTTP client
-	pub client: Client<HttpsConnector<HttpConnector>, Body>,
-	/// Tokio core
-	core: RefCell<Core>,
-	/// How to ratelimit (burst or steady)
-	pub limit: Cell<LimitMethod>,
-	/// Requests sent in the past ratelimit period
-	reqs: Cell<i32>,
-	/// Requests remaining
-	remaining: Cell<Option<i32>>,
-	/// Time when request amount will reset
-	reset_time: Cell<Instant>,
+	/// The hosts requests are sent to
+	hosts: RwLock<Hosts>,
+	/// Transport used to send HTTP requests
+	transport: Arc<dyn Transport>,
+	/// Decides when requests can be sent, possibly shared with other connections
+	ratelimiter: RwLock<Arc<dyn RateLimiter>>,
+	/// How to retry failed requests
+	retry: RwLock<RetryPolicy>,
+	/// How long writes wait out a RATELIMIT error for in total, if at all
+	write_wait: RwLock<Option<Duration>>,
+	/// How long requests wait for a response, including its body, if they have a limit
+	timeout: RwLock<Option<Duration>>,
+	/// The token refresh currently in progress, which other requests wait on
+	refreshing: Mutex<Option<SharedRefresh>>,
+	/// Authorizations that were started and are waiting for a code, by their state string
+	pub(crate) pending: Mutex<HashMap<String, PendingAuth>>,
+	/// Where the authorization is saved whenever it changes, if anywhere
+	store: RwLock<Option<Arc<dyn TokenStore>>>,
 }
 
+/// A future resolving to reddit's json response to a request
+pub type RequestFuture<'a> = Box<dyn Future<Item = Value, Error = Error> + Send + 'a>;
+
+/// A token refresh in progress, which any amount of requests can wait on
+type SharedRefresh = Shared<RequestFuture<'static>>;
+
 impl Connection {
 	/// Creates a new connection instance to reddit
 	/// # Arguments
@@ -65,179 +241,656 @@ impl Connection {
 	/// * `appversion` - The version of the app
 	/// * `appauthor` - The author of the app (should be in reddit form as /u/<username>)
 	pub fn new(appname: &str, appversion: &str, appauthor: &str) -> Result<Connection, Error> {
-		let useragent = HeaderValue::from_str(&format!("linux:{}:{} (by {})", appname, appversion, appauthor)).unwrap();
-		let core = Core::new()?;
-		let client = Client::builder().build(HttpsConnector::new(1)?);
+		Connection::with_transport(appname, appversion, appauthor, Arc::new(HyperTransport::new()?))
+	}
+
+	/// Creates a new connection instance that sends its requests through a custom transport
+	/// # Arguments
+	/// * `appname` - The name of the app
+	/// * `appversion` - The version of the app
+	/// * `appauthor` - The author of the app (should be in reddit form as /u/<username>)
+	/// * `transport` - The transport to send requests with, such as a `MockTransport`
+	pub fn with_transport(appname: &str, appversion: &str, appauthor: &str, transport: Arc<dyn Transport>) -> Result<Connection, Error> {
+		Connection::with_useragent(&format!("linux:{}:{} (by {})", appname, appversion, appauthor), transport)
+	}
+
+	/// Creates a new connection instance with a complete user agent, such as one from a config file
+	/// # Arguments
+	/// * `useragent` - The user agent to send with every request
+	/// * `transport` - The transport to send requests with
+	pub(crate) fn with_useragent(useragent: &str, transport: Arc<dyn Transport>) -> Result<Connection, Error> {
+		let useragent = HeaderValue::from_str(useragent)?;
 		Ok(Connection {
-			auth: None,
+			auth: RwLock::new(None),
 			useragent,
-			client,
-			core: RefCell::new(core),
-			limit: Cell::new(LimitMethod::Steady),
-			reqs: Cell::new(0),
-			remaining: Cell::new(None),
-			reset_time: Cell::new(Instant::now()),
+			hosts: RwLock::new(Hosts::default()),
+			transport,
+			ratelimiter: RwLock::new(Arc::new(TokenBucket::default())),
+			retry: RwLock::new(RetryPolicy::default()),
+			write_wait: RwLock::new(None),
+			timeout: RwLock::new(Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS))),
+			refreshing: Mutex::new(None),
+			pending: Mutex::new(HashMap::new()),
+			store: RwLock::new(None),
 		})
 	}
 
-	/// Send a request to reddit. This is where ratelimiting happens, as well as setting the
-	/// user agent.
-	pub fn run_request(&self, mut req: Request<Body>) -> Result<Value, Error> {
+	/// Send a request to reddit asynchronously. This is where ratelimiting, retrying and timeouts
+	/// happen, as well as setting the user agent. Ratelimiting is done with a timer instead of
+	/// sleeping, so the returned future must be run on a tokio runtime. Each attempt gets the
+	/// timeout of the connection, unless the request has a `RequestTimeout` extension.
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection or an `Arc` holding it. The returned future
+	///   lives as long as this does.
+	/// * `req` - The request to send
+	pub fn send_request<'a, C>(conn: C, req: Request<Body>) -> RequestFuture<'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
 		let req_str = format!("{:?}", req);
+		let policy = conn.retry_policy();
+		let retries = policy.retries(req.method());
+		let timeout = match req.extensions().get::<RequestTimeout>() {
+			Some(&RequestTimeout(timeout)) => timeout,
+			None => conn.timeout(),
+		};
+
+		// Set useragent
+		let mut req = req;
+		req.headers_mut().insert(header::USER_AGENT, conn.useragent.clone());
 
-		// Ratelimit based on method chosen type
-		match self.limit.get() {
-			LimitMethod::Steady => {
-				// Check if we have a remaining limit
-				if let Some(remaining) = self.remaining.get() {
-					// If the reset time is in the future
-					if Instant::now() < self.reset_time.get() {
-						trace!("Ratelimiting in steady mode for {:?}", self.reset_time.get() - Instant::now());
-						// Sleep for the amount of time until reset divided by how many requests we have for steady sending
-						thread::sleep((self.reset_time.get() - Instant::now()).checked_div(remaining as u32).unwrap());
+		// The body is kept around so the request can be sent again if it fails
+		Box::new(BufferedRequest::buffer(req).and_then(move |req| {
+			future::loop_fn(1, move |attempt| {
+				let policy = policy.clone();
+				Connection::send_once(conn.clone(), req.to_request(), timeout).then(move |result| -> Box<dyn Future<Item = Loop<Response<Body>, u32>, Error = Error> + Send> {
+					let wait = if retries && attempt < policy.max_attempts {
+						match result {
+							Ok(ref response) => policy.delay_for(attempt, response),
+							Err(_) => Some(policy.backoff(attempt)),
+						}
+					} else {
+						None
+					};
+
+					match wait {
+						Some(wait) => {
+							match result {
+								Ok(ref response) => warn!("Got response {}, retrying in {:?}", response.status(), wait),
+								Err(ref e) => warn!("Request failed ({}), retrying in {:?}", e, wait),
+							}
+							Box::new(Delay::new(Instant::now() + wait).map_err(Error::from).map(move |_| Loop::Continue(attempt + 1)))
+						}
+						None => Box::new(future::result(result.map(Loop::Break))),
 					}
-					// Else we must have already passed reset time and we will get a new one after this request
+				})
+			})
+		}).and_then(move |response| parse_response(req_str, response)))
+	}
+
+	/// Sends a request once, after waiting for the ratelimit, and receives the response
+	fn send_once<'a, C>(conn: C, req: Request<Body>, timeout: Option<Duration>) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send + 'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		let conn_clone = conn.clone();
+
+		// Ratelimit based on method chosen type, once the future is actually polled
+		let ratelimit = future::lazy(move || -> Box<dyn Future<Item = (), Error = Error> + Send> {
+			match conn_clone.ratelimiter().acquire() {
+				Some(wait) => Box::new(Delay::new(Instant::now() + wait).map_err(Error::from)),
+				None => Box::new(future::ok(())),
+			}
+		});
+
+		let transport = Arc::clone(&conn.transport);
+		let response = ratelimit.and_then(move |_| {
+			// Log the request
+			trace!("Sending request {:?}", req);
+
+			// Execute the request!
+			let req_str = format!("{} {}", req.method(), req.uri());
+			receive(transport.request(req), req_str, timeout)
+		});
+
+		Box::new(response.map(move |response| {
+			conn.ratelimiter().update(response.headers());
+			response
+		}))
+	}
+
+	/// Send a request to reddit asynchronously with authorization headers, refreshing the token
+	/// first if necessary. If the token is already being refreshed by another request, this waits
+	/// for that refresh instead of starting a new one. If reddit rejects the token anyway, it is
+	/// refreshed and the request is sent once more.
+	/// # Arguments
+	/// * `conn` - Either a reference to the connection or an `Arc` holding it
+	/// * `req` - The request to send
+	pub fn send_auth_request<'a, C>(conn: C, req: Request<Body>) -> RequestFuture<'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		// The body is kept around so the request can be sent again if the token is rejected
+		Box::new(BufferedRequest::buffer(req).and_then(move |req| {
+			Connection::send_refreshed(conn.clone(), req.to_request(), false).or_else(move |e| -> RequestFuture<'a> {
+				let rejected = match e.downcast_ref::<RedditError>() {
+					Some(&RedditError::Unauthorized { .. }) => conn.auth.read().unwrap().as_ref().is_some_and(OAuth::can_reauthorize),
+					_ => false,
+				};
+				if rejected {
+					warn!("Token was rejected, reauthorizing and trying again");
+					Connection::send_refreshed(conn, req.to_request(), true)
+				} else {
+					Box::new(future::err(e))
+				}
+			})
+		}))
+	}
+
+	/// Sends a request with authorization headers, refreshing the token first if it needs to be or
+	/// if `force` is set
+	fn send_refreshed<'a, C>(conn: C, req: Request<Body>, force: bool) -> RequestFuture<'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		let refresh = {
+			let mut refreshing = conn.refreshing.lock().unwrap();
+			match *refreshing {
+				Some(ref refresh) => Some(refresh.clone()),
+				None => {
+					let refresh_req = match *conn.auth.read().unwrap() {
+						Some(ref auth) if force || auth.needs_refresh() => match auth.refresh_request(&conn) {
+							Ok(refresh_req) => refresh_req,
+							Err(e) => return Box::new(future::err(e)),
+						},
+						Some(_) => None,
+						None => return Box::new(future::err(Error::from(RedditError::Forbidden { request: format!("{:?}", req) }))),
+					};
+					refresh_req.map(|refresh_req| conn.start_refresh(&mut refreshing, refresh_req))
 				}
 			}
-			LimitMethod::Burst => {
-				// Check if we have a remaining limit
-				if let Some(remaining) = self.remaining.get() {
-					// If we have none remaining and we haven't passed the request limit, sleep till we do
-					if remaining <= 0 && self.reset_time.get() > Instant::now() {
-						trace!("Ratelimiting in burst mode for {:?}", self.reset_time.get() - Instant::now());
-						thread::sleep(self.reset_time.get() - Instant::now());
+		};
+
+		if let Some(refresh) = refresh {
+			let conn_clone = conn.clone();
+			Box::new(
+				refresh
+					.then(move |result| conn_clone.finish_refresh(result))
+					.and_then(move |_| Connection::send_authorized(conn, req)),
+			)
+		} else {
+			Connection::send_authorized(conn, req)
+		}
+	}
+
+	/// Starts sending the request that refreshes the token, and stores it so requests sent in the
+	/// meantime can wait on it
+	fn start_refresh(&self, refreshing: &mut Option<SharedRefresh>, mut refresh_req: Request<Body>) -> SharedRefresh {
+		refresh_req.headers_mut().insert(header::USER_AGENT, self.useragent.clone());
+		let req_str = format!("{:?}", refresh_req);
+
+		trace!("Refreshing token with request {:?}", refresh_req);
+		let target = format!("{} {}", refresh_req.method(), refresh_req.uri());
+		let future: RequestFuture<'static> = Box::new(receive(self.transport.request(refresh_req), target, self.timeout()).and_then(move |response| parse_response(req_str, response)));
+		let refresh = future.shared();
+		*refreshing = Some(refresh.clone());
+		refresh
+	}
+
+	/// Applies the result of a token refresh. Every request waiting on the refresh calls this, but
+	/// only the first one to do so updates the token.
+	fn finish_refresh(&self, result: Result<SharedItem<Value>, SharedError<Error>>) -> Result<(), Error> {
+		let mut refreshing = self.refreshing.lock().unwrap();
+		match result {
+			Ok(response) => {
+				if refreshing.take().is_some() {
+					match *self.auth.write().unwrap() {
+						Some(ref mut auth) => {
+							auth.apply_refresh(&response)?;
+							self.save_auth(auth);
+						}
+						None => return Err(Error::from(RedditError::AuthError)),
 					}
 				}
+				Ok(())
+			}
+			Err(e) => {
+				// Let the next request try again
+				refreshing.take();
+				Err(format_err!("Failed to refresh token: {}", *e))
 			}
+		}
+	}
+
+	/// Adds the bearer token to a request and sends it, without checking if it needs to be refreshed
+	fn send_authorized<'a, C>(conn: C, mut req: Request<Body>) -> RequestFuture<'a>
+	where
+		C: Deref<Target = Connection> + Clone + Send + 'a,
+	{
+		// The token is missing if it expired and can't be refreshed
+		let token = match *conn.auth.read().unwrap() {
+			Some(ref auth) => auth.token(),
+			None => return Box::new(future::err(Error::from(RedditError::Forbidden { request: format!("{:?}", req) }))),
 		};
+		match token {
+			Some(token) => {
+				req.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+				Connection::send_request(conn, req)
+			}
+			None => Box::new(future::err(Error::from(RedditError::TokenExpired))),
+		}
+	}
 
-		// Set useragent
-		req.headers_mut().insert(header::USER_AGENT, self.useragent.clone());
-
-		// Log the request
-		trace!("Sending request {:?}", req);
-
-		// Execute the request!
-		let response = self.client.request(req);
-		let response = self.core.borrow_mut().run(response)?;
-
-		// Update values from response ratelimiting headers
-		if let Some(reqs_used) = response.headers().get("x-ratelimit-used") {
-			let reqs_used = reqs_used.to_str().unwrap().parse::<f32>().unwrap().round() as i32;
-			trace!("Used {} of requests in ratelimit period", reqs_used);
-			self.reqs.set(reqs_used);
-		}
-		if let Some(reqs_remaining) = response.headers().get("x-ratelimit-remaining") {
-			let reqs_remaining = reqs_remaining.to_str().unwrap().parse::<f32>().unwrap().round() as i32;
-			trace!("Have {} requests remaining in ratelimit period", reqs_remaining);
-			self.remaining.set(Some(reqs_remaining));
-		}
-		if let Some(secs_remaining) = response.headers().get("x-ratelimit-reset") {
-			let secs_remaining = secs_remaining.to_str().unwrap().parse::<f32>().unwrap().round() as u64;
-			trace!("Have {} seconds remaining to ratelimit reset", secs_remaining);
-			self.reset_time.set(Instant::now() + Duration::new(secs_remaining, 0));
-		}
-		trace!("Ratelimiting:\n\tRequests used: {:?}\n\tRequests remaining: {:?}\n\tReset time: {:?}\n\tNow: {:?}", self.reqs.get(), self.remaining.get(), self.reset_time.get(), Instant::now());
-
-		let response_str = format!("{:?}", response);
-		let get_body = |response: Response<Body>| -> Result<String, Error> {
-			let body = self.core.borrow_mut().run(response.into_body().concat2())?;
-			let body: String = String::from_utf8_lossy(&body).into();
-			Ok(body)
+	/// Send a request to reddit, blocking until the response arrives. This is where ratelimiting
+	/// happens, as well as setting the user agent.
+	pub fn run_request(&self, req: Request<Body>) -> Result<Value, Error> {
+		self.run(Connection::send_request(self, req))
+	}
+
+	/// Send a request to reddit with authorization headers, blocking until the response arrives
+	pub fn run_auth_request(&self, req: Request<Body>) -> Result<Value, Error> {
+		self.run(Connection::send_auth_request(self, req))
+	}
+
+	/// Runs a future to completion on a runtime local to the current thread, blocking it until
+	/// the future is done. This can be called from many threads at once. Each thread makes its
+	/// runtime the first time it blocks, and reuses it for every call after that.
+	///
+	/// Note: this can't be called from within a future that is already being run on a runtime.
+	pub fn run<F: Future<Error = Error>>(&self, future: F) -> Result<F::Item, Error> {
+		BLOCKING_RUNTIME.with(|runtime| {
+			let mut runtime = runtime.borrow_mut();
+			if runtime.is_none() {
+				*runtime = Some(Runtime::new()?);
+			}
+			runtime.as_mut().unwrap().block_on(future)
+		})
+	}
+
+	/// Refreshes the token of the current authorization if it has expired, blocking until done
+	pub fn refresh(&self) -> Result<(), Error> {
+		let refresh_req = match *self.auth.read().unwrap() {
+			Some(ref auth) => auth.refresh_request(self)?,
+			None => return Err(Error::from(RedditError::AuthError)),
 		};
 
-		if !response.status().is_success() {
+		if let Some(refresh_req) = refresh_req {
+			let response = self.run_request(refresh_req)?;
+			if let Some(ref mut auth) = *self.auth.write().unwrap() {
+				auth.apply_refresh(&response)?;
+				self.save_auth(auth);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Sets the authorization, and saves it to the token store if there is one
+	pub fn set_auth(&self, auth: OAuth) {
+		self.save_auth(&auth);
+		*self.auth.write().unwrap() = Some(auth);
+	}
+
+	/// Drops the authorization, and forgets the one saved in the token store if there is one. The
+	/// tokens stay valid until they expire, use `App::revoke` to invalidate them.
+	pub fn clear_auth(&self) {
+		*self.auth.write().unwrap() = None;
+		if let Some(ref store) = *self.store.read().unwrap() {
+			if let Err(e) = store.clear() {
+				error!("Failed to clear saved authorization: {}", e);
+			}
+		}
+	}
+
+	/// Sets where the authorization is saved whenever it changes. `None` stops saving it.
+	pub fn set_token_store(&self, store: Option<Arc<dyn TokenStore>>) {
+		*self.store.write().unwrap() = store;
+	}
+
+	/// Returns the token store the authorization is saved to, if there is one
+	pub fn token_store(&self) -> Option<Arc<dyn TokenStore>> {
+		self.store.read().unwrap().clone()
+	}
+
+	/// Saves an authorization to the token store, if there is one. Failing to save it doesn't
+	/// stop the request that caused it, so failures are only logged.
+	pub(crate) fn save_auth(&self, auth: &OAuth) {
+		if let Some(ref store) = *self.store.read().unwrap() {
+			if let Err(e) = store.save(auth) {
+				error!("Failed to save authorization: {}", e);
+			}
+		}
+	}
+
+	/// Returns when the current token expires, if the connection is authorized and the expiry is
+	/// known. The token is renewed automatically shortly before then if the authorization allows it.
+	pub fn token_expiry(&self) -> Option<Instant> {
+		self.auth.read().unwrap().as_ref().and_then(OAuth::expire_instant)
+	}
+
+	/// Sets the longest total time writes wait out reddit's per-account ratelimit for before
+	/// giving up. `None` turns waiting off.
+	pub fn set_write_ratelimit_wait(&self, max_wait: Option<Duration>) {
+		*self.write_wait.write().unwrap() = max_wait;
+	}
+
+	/// Returns the longest total time writes wait out reddit's per-account ratelimit for
+	pub fn write_ratelimit_wait(&self) -> Option<Duration> {
+		*self.write_wait.read().unwrap()
+	}
+
+	/// Sets how long requests wait for a response, including its body, before giving up with
+	/// `RedditError::Timeout`. Each retry gets the whole time again. `None` waits as long as it
+	/// takes.
+	pub fn set_timeout(&self, timeout: Option<Duration>) {
+		*self.timeout.write().unwrap() = timeout;
+	}
+
+	/// Returns how long requests wait for a response
+	pub fn timeout(&self) -> Option<Duration> {
+		*self.timeout.read().unwrap()
+	}
+
+	/// Sets how failed requests are retried
+	pub fn set_retry_policy(&self, policy: RetryPolicy) {
+		*self.retry.write().unwrap() = policy;
+	}
+
+	/// Returns how failed requests are retried
+	pub fn retry_policy(&self) -> RetryPolicy {
+		self.retry.read().unwrap().clone()
+	}
+
+	/// Set's the ratelimiting method. This sets it on the ratelimiter, so every connection
+	/// sharing the ratelimiter through `set_ratelimiter` gets the new method too.
+	pub fn set_limit(&self, limit: LimitMethod) {
+		self.ratelimiter().set_method(limit);
+	}
+
+	/// Replaces the ratelimiter, for example with one shared with other connections. The
+	/// ratelimiting method is part of the ratelimiter, so it's shared as well.
+	pub fn set_ratelimiter(&self, ratelimiter: Arc<dyn RateLimiter>) {
+		*self.ratelimiter.write().unwrap() = ratelimiter;
+	}
+
+	/// Returns the ratelimiter, which can be given to other connections to share it
+	pub fn ratelimiter(&self) -> Arc<dyn RateLimiter> {
+		Arc::clone(&self.ratelimiter.read().unwrap())
+	}
+
+	/// Returns the current ratelimit budget
+	pub fn ratelimit_budget(&self) -> Budget {
+		self.ratelimiter().budget()
+	}
+
+	/// Sets the hosts requests are sent to
+	pub fn set_hosts(&self, hosts: Hosts) {
+		*self.hosts.write().unwrap() = hosts;
+	}
+
+	/// Returns the hosts requests are sent to
+	pub fn hosts(&self) -> Hosts {
+		self.hosts.read().unwrap().clone()
+	}
+
+	/// Creates a url to the authorized API from a path such as `/api/v1/me/.json`
+	pub fn oauth_url(&self, path: &str) -> String {
+		format!("{}{}", self.hosts.read().unwrap().oauth, path)
+	}
+
+	/// Creates a url to the public (unauthorized) API from a path such as `/r/rust/.json`
+	pub fn public_url(&self, path: &str) -> String {
+		format!("{}{}", self.hosts.read().unwrap().public, path)
+	}
+
+	/// Creates a url for reading from the API from a path such as `/r/rust/.json`. Once the
+	/// connection is authorized in any way that allows reading, reads go through the authorized
+	/// API for its higher ratelimit, otherwise they go through the public API.
+	pub fn read_url(&self, path: &str) -> String {
+		if self.reads_authorized() {
+			self.oauth_url(path)
+		} else {
+			self.public_url(path)
+		}
+	}
+
+	/// Whether reads should go through the authorized API, which is when the connection is
+	/// authorized and the `read` scope wasn't withheld
+	pub fn reads_authorized(&self) -> bool {
+		match *self.auth.read().unwrap() {
+			Some(ref auth) => auth.require_scope("read").is_ok(),
+			None => false,
+		}
+	}
+
+	/// Returns an error if the current authorization is known to lack a scope. Requests without
+	/// any authorization are left for reddit to refuse.
+	/// # Arguments
+	/// * `scope` - The name of the scope, such as `submit`
+	pub fn require_scope(&self, scope: &str) -> Result<(), Error> {
+		match *self.auth.read().unwrap() {
+			Some(ref auth) => auth.require_scope(scope).map_err(Error::from),
+			None => Ok(()),
+		}
+	}
+
+	/// Creates a url to the OAuth token host from a path such as `/api/v1/access_token/.json`
+	pub fn token_url(&self, path: &str) -> String {
+		format!("{}{}", self.hosts.read().unwrap().token, path)
+	}
+
+	/// Returns the transport requests are sent with
+	pub fn transport(&self) -> &Arc<dyn Transport> {
+		&self.transport
+	}
+
+	/// Remembers an authorization that is waiting for a code, and returns the state string
+	/// identifying it. Authorizations that were never completed are forgotten after 10 minutes,
+	/// and the oldest ones are dropped to make room if too many are waiting.
+	pub(crate) fn add_pending_auth(&self, pending: PendingAuth) -> String {
+		let state = rand::thread_rng().gen_ascii_chars().take(16).collect::<String>();
+		let mut waiting = self.pending.lock().unwrap();
+		prune_pending(&mut waiting);
+		while waiting.len() >= MAX_PENDING_AUTHS {
+			let oldest = match waiting.iter().min_by_key(|&(_, auth)| auth.started) {
+				Some((state, _)) => state.clone(),
+				None => break,
+			};
+			waiting.remove(&oldest);
+		}
+		waiting.insert(state.clone(), pending);
+		state
+	}
+
+	/// Removes and returns the authorization identified by a state string, if there is one that
+	/// hasn't expired
+	pub(crate) fn take_pending_auth(&self, state: &str) -> Option<PendingAuth> {
+		let mut waiting = self.pending.lock().unwrap();
+		prune_pending(&mut waiting);
+		waiting.remove(state)
+	}
+
+	/// Removes and returns the installed app authorization waiting for a code, if there is exactly
+	/// one that hasn't expired. Used when the user only has the code and not the state.
+	pub(crate) fn take_only_pending_installed_auth(&self) -> Option<PendingAuth> {
+		let mut pending = self.pending.lock().unwrap();
+		// An abandoned attempt would otherwise make every later one ambiguous
+		prune_pending(&mut pending);
+		let states: Vec<String> = pending.iter().filter(|&(_, auth)| auth.secret.is_none()).map(|(state, _)| state.clone()).collect();
+		if states.len() == 1 {
+			pending.remove(&states[0])
+		} else {
+			None
+		}
+	}
+}
+
+/// A request with its body read into memory, so it can be sent more than once
+pub(crate) struct BufferedRequest {
+	method: Method,
+	uri: Uri,
+	version: Version,
+	headers: HeaderMap,
+	timeout: Option<RequestTimeout>,
+	body: Vec<u8>,
+}
+
+impl BufferedRequest {
+	/// Reads the body of a request into memory
+	pub(crate) fn buffer(req: Request<Body>) -> impl Future<Item = BufferedRequest, Error = Error> + Send {
+		let (parts, body) = req.into_parts();
+		body.concat2().map_err(Error::from).map(move |body| BufferedRequest {
+			timeout: parts.extensions.get::<RequestTimeout>().cloned(),
+			method: parts.method,
+			uri: parts.uri,
+			version: parts.version,
+			headers: parts.headers,
+			body: body.to_vec(),
+		})
+	}
+
+	/// Creates a new copy of the request
+	pub(crate) fn to_request(&self) -> Request<Body> {
+		let mut req = Request::new(Body::from(self.body.clone()));
+		*req.method_mut() = self.method.clone();
+		*req.uri_mut() = self.uri.clone();
+		*req.version_mut() = self.version;
+		*req.headers_mut() = self.headers.clone();
+		if let Some(timeout) = self.timeout {
+			req.extensions_mut().insert(timeout);
+		}
+		req
+	}
+}
+
+/// Waits for a response and reads its whole body, giving up with `RedditError::Timeout` if that
+/// takes longer than the timeout
+fn receive(response: TransportFuture, req_str: String, timeout: Option<Duration>) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+	let response = response.and_then(|response| {
+		let (parts, body) = response.into_parts();
+		body.concat2().map_err(Error::from).map(move |body| Response::from_parts(parts, Body::from(body)))
+	});
+
+	match timeout {
+		Some(timeout) => Box::new(Timeout::new(response, timeout).map_err(move |e| {
+			if e.is_elapsed() {
+				Error::from(RedditError::Timeout { request: req_str, timeout })
+			} else if e.is_timer() {
+				format_err!("Timer failed while waiting for a response: {}", e)
+			} else {
+				e.into_inner().expect("error is neither elapsed nor a timer error")
+			}
+		})),
+		None => Box::new(response),
+	}
+}
+
+/// Reads the body of a response, turning error statuses into errors and parsing it as json
+fn parse_response(req_str: String, response: Response<Body>) -> RequestFuture<'static> {
+	let response_str = format!("{:?}", response);
+	let status = response.status();
+	Box::new(response.into_body().concat2().map_err(Error::from).and_then(move |body| {
+		let body: String = String::from_utf8_lossy(&body).into();
+
+		// Some endpoints respond with nothing at all, such as 204 No Content
+		if status.is_success() && body.trim().is_empty() {
+			trace!("Got empty successful response: {:?}", response_str);
+			return Ok(Value::Null);
+		}
+
+		if status == StatusCode::UNAUTHORIZED {
+			error!("Got error response: {}", response_str);
+			return Err(Error::from(RedditError::Unauthorized {
+				request: req_str,
+				response: format!("Reponse: {}\nResponse body: {:?}", response_str, body),
+			}));
+		}
+		if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+			error!("Got error response: {}", response_str);
+			return Err(Error::from(RedditError::ServerError {
+				request: req_str,
+				response: format!("Reponse: {}\nResponse body: {:?}", response_str, body),
+			}));
+		}
+		if !status.is_success() {
 			error!("Got error response: {}", response_str);
 			return Err(Error::from(RedditError::BadRequest {
 				request: req_str,
-				response: format!("Reponse: {}\nResponse body: {:?}", response_str, get_body(response)?),
+				response: format!("Reponse: {}\nResponse body: {:?}", response_str, body),
 			}));
 		}
 
-		let body = get_body(response)?;
-
 		match json::from_str(&body) {
 			Ok(r) => {
+				// Reddit reports rejected writes in the body of a successful response
+				if let Some(e) = RedditError::from_api_errors(&r) {
+					error!("Got error in response: {}", e);
+					return Err(Error::from(e));
+				}
 				trace!("Got successful response: {:?}\nBody: {}", response_str, body);
 				Ok(r)
 			}
 			Err(_) => Err(Error::from(RedditError::BadResponse { request: req_str, response: body })),
 		}
+	}))
+}
+
+/// A urlencoded form to send as the body of a request. Every key and value is encoded, so they
+/// should be passed in as they are. Parameters are sent in the order they were added, and the same
+/// key can be added more than once.
+///
+/// ```rust
+/// # use orca::net::Form;
+/// let form = Form::new().param("text", "Tanks & Banks").param("api_type", "json");
+/// assert_eq!(form.encode(), "text=Tanks+%26+Banks&api_type=json");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Form {
+	params: Vec<(String, String)>,
+}
+
+impl Form {
+	/// Creates an empty form
+	pub fn new() -> Form {
+		Form::default()
 	}
 
-	/// Send a request to reddit with authorization headers
-	pub fn run_auth_request(&self, mut req: Request<Body>) -> Result<Value, Error> {
-		if let Some(ref auth) = self.auth {
-			let req_str = format!("{:?}", req);
-			req.headers_mut().insert(
-				header::AUTHORIZATION,
-				HeaderValue::from_str(&format!(
-					"Bearer {}",
-					match *auth {
-						OAuth::Script {
-							id: ref _id,
-							secret: ref _secret,
-							username: ref _username,
-							password: ref _password,
-							ref token,
-						} => token.to_string(),
-						OAuth::InstalledApp {
-							id: ref _id,
-							redirect: ref _redirect,
-							ref token,
-							ref refresh_token,
-							ref expire_instant,
-						} => {
-							// If the token can expire and we are able to refresh it
-							if let (Some(_refresh_token), Some(expire_instant)) = (refresh_token.borrow().clone(), expire_instant.get()) {
-								// If the token's expired, refresh it
-								if Instant::now() > expire_instant {
-									auth.refresh(self)?;
-								}
-								token.borrow().to_string()
-							} else if let Some(expire_instant) = expire_instant.get() {
-								if Instant::now() > expire_instant {
-									return Err(Error::from(RedditError::Forbidden { request: format!("{:?}", req_str) }));
-								} else {
-									token.borrow().to_string()
-								}
-							} else {
-								token.borrow().to_string()
-							}
-						}
-					}
-				))
-				.unwrap(),
-			);
-			self.run_request(req)
-		} else {
-			Err(Error::from(RedditError::Forbidden { request: format!("{:?}", req) }))
-		}
+	/// Adds a parameter to the form
+	/// # Arguments
+	/// * `key` - The name of the parameter
+	/// * `value` - The value of the parameter, not encoded
+	pub fn param<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Form {
+		self.params.push((key.into(), value.into()));
+		self
 	}
 
-	/// Set's the ratelimiting method
-	pub fn set_limit(&self, limit: LimitMethod) {
-		self.limit.set(limit);
+	/// Adds a parameter to the form if there is a value for it
+	pub fn param_opt<K: Into<String>, V: Into<String>>(self, key: K, value: Option<V>) -> Form {
+		match value {
+			Some(value) => self.param(key, value),
+			None => self,
+		}
 	}
 
-	/// Returns a reference to the tokio core in a RefCell
-	pub fn get_core(&self) -> &RefCell<Core> {
-		&self.core
+	/// Returns the form in urlencoded form
+	pub fn encode(&self) -> String {
+		form_urlencoded::Serializer::new(String::new()).extend_pairs(self.params.iter()).finish()
 	}
 }
 
-/// Creates a HTTP/hyper Body from a hashmap, in urlencoded form.
-pub fn body_from_map<S: BuildHasher>(map: &HashMap<&str, &str, S>) -> Body {
-	let mut body_str = String::new();
+impl From<Form> for Body {
+	fn from(form: Form) -> Body {
+		let body_str = form.encode();
+		trace!("Setup body: \n{}\n", body_str);
 
-	for (i, item) in map.iter().enumerate() {
-		// Push the paramater to the body with an & at the end unless it's the last parameter
-		body_str.push_str(&format!("{}={}{}", item.0, item.1, if i < map.len() - 1 { "&" } else { "" }));
+		Body::from(body_str)
 	}
+}
 
-	trace!("Setup body: \n{}\n", body_str);
-
-	Body::from(body_str)
+/// Creates a HTTP/hyper Body from a hashmap, in urlencoded form. The order of the parameters
+/// isn't kept, so `Form` should be used instead.
+pub fn body_from_map<S: BuildHasher>(map: &HashMap<&str, &str, S>) -> Body {
+	map.iter().fold(Form::new(), |form, (key, value)| form.param(*key, *value)).into()
 }
 
 /// Creates a url with encoded parameters from hashmap. Right now it's kinda hacky
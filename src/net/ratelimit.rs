@@ -0,0 +1,205 @@
+//! # Ratelimiting
+//! Reddit allows a limited amount of requests per client in each ratelimit period (600 requests
+//! every 10 minutes at the time of writing), and reports how much of it is left in the
+//! `x-ratelimit-*` headers of every response. A `Connection` asks its `RateLimiter` how long to
+//! wait before every request, and tells it about the headers of every response.
+//!
+//! The default limiter is a `TokenBucket`. Since a budget belongs to an OAuth client and not to
+//! a connection, one limiter can be shared between all the connections using the same client by
+//! putting it in an `Arc` and passing it to each of them.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::HeaderMap;
+
+/// How to ratelimit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LimitMethod {
+	/// Wait an even amount of time between each request
+	Steady,
+	/// Fire off requests as they come. It's possible there will be a long waiting time for the
+	/// next ratelimit period if too many are fired off at once.
+	Burst,
+}
+
+/// The state of a ratelimit budget
+#[derive(Copy, Clone, Debug)]
+pub struct Budget {
+	/// Requests sent in the current ratelimit period
+	pub used: i32,
+	/// Requests remaining in the current ratelimit period, if known
+	pub remaining: Option<i32>,
+	/// Time when the ratelimit period resets
+	pub reset_time: Instant,
+}
+
+/// A snapshot of the state of a ratelimiter and the last decision it made
+#[derive(Copy, Clone, Debug)]
+pub struct Snapshot {
+	/// How requests are spread out
+	pub method: LimitMethod,
+	/// The current budget
+	pub budget: Budget,
+	/// How long the last request had to wait, if it had to
+	pub last_wait: Option<Duration>,
+	/// How many requests had to wait so far
+	pub waited: u64,
+}
+
+/// Something that decides when requests can be sent to stay within reddit's ratelimit
+pub trait RateLimiter: Send + Sync {
+	/// Reserves the next request, returning how long to wait before sending it if it can't be
+	/// sent right away
+	fn acquire(&self) -> Option<Duration>;
+
+	/// Updates the budget from the `x-ratelimit-*` headers of a response
+	fn update(&self, headers: &HeaderMap);
+
+	/// Returns the current budget
+	fn budget(&self) -> Budget;
+
+	/// Returns the current state of the ratelimiter
+	fn snapshot(&self) -> Snapshot;
+
+	/// Sets how requests are spread out over the ratelimit period, for every connection using
+	/// this ratelimiter
+	fn set_method(&self, method: LimitMethod);
+}
+
+/// A ratelimiter that treats the remaining requests of the ratelimit period as tokens. Sending a
+/// request takes a token, and the bucket is refilled from the headers of every response.
+pub struct TokenBucket {
+	state: Mutex<BucketState>,
+}
+
+struct BucketState {
+	/// How to ratelimit (burst or steady)
+	method: LimitMethod,
+	/// Budget of the current ratelimit period
+	budget: Budget,
+	/// Time the last request was scheduled to be sent at
+	last_send: Instant,
+	/// How long the last request had to wait
+	last_wait: Option<Duration>,
+	/// How many requests had to wait
+	waited: u64,
+}
+
+impl TokenBucket {
+	/// Creates a token bucket with an unknown budget, which is filled in from the first response
+	/// # Arguments
+	/// * `method` - How to spread out requests
+	pub fn new(method: LimitMethod) -> TokenBucket {
+		TokenBucket {
+			state: Mutex::new(BucketState {
+				method,
+				budget: Budget {
+					used: 0,
+					remaining: None,
+					reset_time: Instant::now(),
+				},
+				last_send: Instant::now(),
+				last_wait: None,
+				waited: 0,
+			}),
+		}
+	}
+}
+
+impl Default for TokenBucket {
+	fn default() -> TokenBucket {
+		TokenBucket::new(LimitMethod::Steady)
+	}
+}
+
+impl RateLimiter for TokenBucket {
+	fn acquire(&self) -> Option<Duration> {
+		let mut state = self.state.lock().unwrap();
+		let now = Instant::now();
+
+		let wait = match (state.budget.remaining, state.method) {
+			// We'll get a new reset time after this request if we passed the old one, and we can't
+			// know how long to wait before the first response tells us the budget
+			(None, _) => None,
+			(Some(_), _) if now >= state.budget.reset_time => None,
+			// If we have none remaining, wait till the period resets
+			(Some(remaining), _) if remaining <= 0 => {
+				let send = if state.last_send > state.budget.reset_time { state.last_send } else { state.budget.reset_time };
+				state.last_send = send;
+				Some(send - now)
+			}
+			(Some(remaining), LimitMethod::Steady) => {
+				// Wait for the amount of time until reset divided by how many requests we have for steady sending,
+				// counting from the last request that was scheduled
+				let interval = (state.budget.reset_time - now) / remaining as u32;
+				let send = if state.last_send > now { state.last_send + interval } else { now + interval };
+				state.last_send = send;
+				state.budget.remaining = Some(remaining - 1);
+				Some(send - now)
+			}
+			(Some(remaining), LimitMethod::Burst) => {
+				// Take a token until the response tells us the real amount
+				state.budget.remaining = Some(remaining - 1);
+				None
+			}
+		};
+
+		if let Some(wait) = wait {
+			debug!("Ratelimiting in {:?} mode for {:?} ({:?} requests remaining)", state.method, wait, state.budget.remaining);
+			state.waited += 1;
+		}
+		state.last_wait = wait;
+
+		wait
+	}
+
+	fn update(&self, headers: &HeaderMap) {
+		let mut state = self.state.lock().unwrap();
+
+		if let Some(reqs_used) = parse_header(headers, "x-ratelimit-used") {
+			trace!("Used {} of requests in ratelimit period", reqs_used);
+			state.budget.used = reqs_used.round() as i32;
+		}
+		if let Some(reqs_remaining) = parse_header(headers, "x-ratelimit-remaining") {
+			trace!("Have {} requests remaining in ratelimit period", reqs_remaining);
+			state.budget.remaining = Some(reqs_remaining.round() as i32);
+		}
+		if let Some(secs_remaining) = parse_header(headers, "x-ratelimit-reset") {
+			trace!("Have {} seconds remaining to ratelimit reset", secs_remaining);
+			state.budget.reset_time = Instant::now() + Duration::from_millis((secs_remaining * 1000.0) as u64);
+		}
+		trace!("Ratelimiting:\n\tRequests used: {:?}\n\tRequests remaining: {:?}\n\tReset time: {:?}\n\tNow: {:?}", state.budget.used, state.budget.remaining, state.budget.reset_time, Instant::now());
+	}
+
+	fn budget(&self) -> Budget {
+		self.state.lock().unwrap().budget
+	}
+
+	fn snapshot(&self) -> Snapshot {
+		let state = self.state.lock().unwrap();
+		Snapshot {
+			method: state.method,
+			budget: state.budget,
+			last_wait: state.last_wait,
+			waited: state.waited,
+		}
+	}
+
+	fn set_method(&self, method: LimitMethod) {
+		self.state.lock().unwrap().method = method;
+	}
+}
+
+/// Parses a ratelimiting header as a positive number. Missing headers are ignored, and malformed
+/// ones are logged and ignored.
+fn parse_header(headers: &HeaderMap, name: &str) -> Option<f32> {
+	let value = headers.get(name)?;
+	match value.to_str().ok().and_then(|value| value.trim().parse::<f32>().ok()) {
+		Some(number) if number.is_finite() && number >= 0.0 && number < i32::MAX as f32 => Some(number),
+		_ => {
+			warn!("Ignoring malformed {} header {:?}", name, value);
+			None
+		}
+	}
+}
@@ -0,0 +1,84 @@
+//! # Token stores
+//! Authorizing an installed or web app takes a trip through the browser, so the authorization
+//! should be kept between runs of the program. A `TokenStore` saves the authorization of a
+//! connection whenever it gets a new token, and hands it back when the program starts again.
+//!
+//! `FileTokenStore` keeps the authorization in a json file. It contains everything needed to
+//! act as the user, including the password of script authorizations, so it should be kept
+//! private. On unix the file is only readable and writable by its owner (mode `0600`).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use failure::Error;
+use json;
+
+use net::auth::OAuth;
+
+/// Somewhere to keep an authorization between runs of the program
+pub trait TokenStore: Send + Sync {
+	/// Loads the saved authorization, if there is one
+	fn load(&self) -> Result<Option<OAuth>, Error>;
+
+	/// Saves an authorization, replacing the one saved before
+	fn save(&self, auth: &OAuth) -> Result<(), Error>;
+
+	/// Forgets the saved authorization
+	fn clear(&self) -> Result<(), Error>;
+}
+
+/// A token store that keeps the authorization in a json file. On unix the file is created so only
+/// its owner can read it, since it holds the client secret, refresh token and script password.
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+	path: PathBuf,
+}
+
+impl FileTokenStore {
+	/// Creates a store that keeps the authorization in a file, which doesn't have to exist yet
+	/// # Arguments
+	/// * `path` - The file to keep the authorization in
+	pub fn new<P: Into<PathBuf>>(path: P) -> FileTokenStore {
+		FileTokenStore { path: path.into() }
+	}
+}
+
+impl TokenStore for FileTokenStore {
+	fn load(&self) -> Result<Option<OAuth>, Error> {
+		let mut contents = String::new();
+		match File::open(&self.path) {
+			Ok(mut file) => file.read_to_string(&mut contents)?,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e.into()),
+		};
+		Ok(Some(json::from_str(&contents)?))
+	}
+
+	fn save(&self, auth: &OAuth) -> Result<(), Error> {
+		// Write to a temporary file first so a crash can't leave a half written file behind
+		let tmp = self.path.with_extension("tmp");
+		// The mode only applies to new files, so a leftover temporary file is removed first
+		match fs::remove_file(&tmp) {
+			Err(ref e) if e.kind() != ErrorKind::NotFound => return Err(format_err!("Could not remove {}: {}", tmp.display(), e)),
+			_ => {}
+		}
+
+		let mut options = OpenOptions::new();
+		options.write(true).create_new(true);
+		#[cfg(unix)]
+		options.mode(0o600);
+		options.open(&tmp)?.write_all(json::to_string_pretty(auth)?.as_bytes())?;
+		fs::rename(&tmp, &self.path)?;
+		Ok(())
+	}
+
+	fn clear(&self) -> Result<(), Error> {
+		match fs::remove_file(&self.path) {
+			Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+			result => Ok(result?),
+		}
+	}
+}
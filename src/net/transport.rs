@@ -0,0 +1,231 @@
+//! # Transports
+//! A `Connection` doesn't talk to the network itself, it hands every request to a `Transport`.
+//! By default this is a `HyperTransport`, which sends requests over HTTPS with hyper from a
+//! background runtime, so the futures it returns can be waited on from any thread. For tests,
+//! a `MockTransport` can be used instead, which serves canned responses from memory so no
+//! credentials or network access are necessary.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use failure::Error;
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use hyper::client::{Client, HttpConnector};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, PROXY_AUTHORIZATION};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use native_tls::TlsConnector;
+use tokio::runtime::Runtime;
+
+use net::proxy::{Proxy, ProxyConnector};
+
+/// The future returned by a `Transport` for a single request
+pub type TransportFuture = Box<dyn Future<Item = Response<Body>, Error = Error> + Send>;
+
+/// Something that can send HTTP requests to reddit on behalf of a `Connection`
+pub trait Transport: Send + Sync {
+	/// Send a request, returning a future that resolves to the response
+	fn request(&self, req: Request<Body>) -> TransportFuture;
+}
+
+/// How a `HyperTransport` connects to reddit
+#[derive(Debug, Clone)]
+pub struct HyperConfig {
+	/// Number of threads resolving host names
+	pub dns_threads: usize,
+	/// Longest time to wait for a connection to be established, or `None` to wait as long as the
+	/// operating system does
+	pub connect_timeout: Option<Duration>,
+	/// The proxy to send requests through, or `None` to connect directly. The default doesn't
+	/// look at the environment, but `HyperTransport::new` does.
+	pub proxy: Option<Proxy>,
+}
+
+impl Default for HyperConfig {
+	fn default() -> HyperConfig {
+		HyperConfig {
+			dns_threads: 4,
+			connect_timeout: None,
+			proxy: None,
+		}
+	}
+}
+
+/// The default transport, which sends requests over HTTPS using a hyper client. Connections are
+/// driven by a runtime owned by the transport, so they keep working no matter which thread is
+/// waiting on a response.
+pub struct HyperTransport {
+	client: Client<HttpsConnector<ProxyConnector>, Body>,
+	runtime: Mutex<Runtime>,
+	proxy: Option<Proxy>,
+}
+
+impl HyperTransport {
+	/// Creates a new hyper transport, which uses the proxy set in the environment if there is one
+	pub fn new() -> Result<HyperTransport, Error> {
+		HyperTransport::with_config(HyperConfig {
+			proxy: Proxy::from_env()?,
+			..HyperConfig::default()
+		})
+	}
+
+	/// Creates a new hyper transport that connects as configured
+	/// # Arguments
+	/// * `config` - How to connect to reddit
+	pub fn with_config(config: HyperConfig) -> Result<HyperTransport, Error> {
+		if config.dns_threads == 0 {
+			return Err(format_err!("At least one DNS thread is needed"));
+		}
+
+		let mut http = HttpConnector::new(config.dns_threads);
+		http.enforce_http(false);
+		http.set_connect_timeout(config.connect_timeout);
+		let connector = HttpsConnector::from((ProxyConnector::new(http, config.proxy.clone()), TlsConnector::new()?));
+
+		let runtime = Runtime::new()?;
+		let client = Client::builder().executor(runtime.executor()).build(connector);
+		Ok(HyperTransport {
+			client,
+			runtime: Mutex::new(runtime),
+			proxy: config.proxy,
+		})
+	}
+}
+
+impl Transport for HyperTransport {
+	fn request(&self, mut req: Request<Body>) -> TransportFuture {
+		// Requests that aren't tunneled carry the proxy's credentials themselves
+		if let Some(ref proxy) = self.proxy {
+			if let (Some("http"), Some(host), Some(authorization)) = (req.uri().scheme_part().map(|scheme| scheme.as_str()), req.uri().host(), proxy.authorization()) {
+				if proxy.applies_to(host) {
+					req.headers_mut().insert(PROXY_AUTHORIZATION, authorization.clone());
+				}
+			}
+		}
+
+		let (sender, receiver) = oneshot::channel();
+		let response = self.client.request(req).then(move |response| {
+			// The receiver is gone if the request was dropped, in which case there's nobody to tell
+			let _ = sender.send(response);
+			Ok(())
+		});
+		self.runtime.lock().unwrap().spawn(response);
+
+		Box::new(receiver.map_err(|_| format_err!("Request was dropped by the runtime")).and_then(|response| response.map_err(Error::from)))
+	}
+}
+
+/// A canned response to be served by a `MockTransport`
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+	/// The status code of the response
+	pub status: StatusCode,
+	/// The headers of the response
+	pub headers: HeaderMap,
+	/// The body of the response
+	pub body: String,
+}
+
+impl MockResponse {
+	/// Creates a response with the given status code and body
+	/// # Arguments
+	/// * `status` - The HTTP status code to respond with
+	/// * `body` - The body to respond with, usually json
+	pub fn new(status: u16, body: &str) -> MockResponse {
+		MockResponse {
+			status: StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+			headers: HeaderMap::new(),
+			body: body.to_string(),
+		}
+	}
+
+	/// Creates a successful (200) response with the given json body
+	pub fn json(body: &str) -> MockResponse {
+		MockResponse::new(200, body)
+	}
+
+	/// Adds a header to the response. Invalid header names or values are ignored.
+	pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+		if let (Ok(name), Ok(value)) = (name.parse::<HeaderName>(), HeaderValue::from_str(value)) {
+			self.headers.append(name, value);
+		}
+		self
+	}
+}
+
+/// A request that was received by a `MockTransport`
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+	/// The HTTP method of the request
+	pub method: Method,
+	/// The uri the request was sent to
+	pub uri: Uri,
+	/// The headers sent with the request
+	pub headers: HeaderMap,
+	/// The body of the request
+	pub body: String,
+}
+
+/// A transport that serves canned responses in the order they were queued, and remembers every
+/// request it received. Useful for testing code that uses orca without talking to reddit.
+#[derive(Default)]
+pub struct MockTransport {
+	responses: Mutex<VecDeque<MockResponse>>,
+	requests: Mutex<Vec<MockRequest>>,
+}
+
+impl MockTransport {
+	/// Creates a mock transport with no responses queued
+	pub fn new() -> MockTransport {
+		MockTransport::default()
+	}
+
+	/// Queues a response to be served to the next request
+	pub fn push(&self, response: MockResponse) {
+		self.responses.lock().unwrap().push_back(response);
+	}
+
+	/// Returns all of the requests received so far, oldest first
+	pub fn requests(&self) -> Vec<MockRequest> {
+		self.requests.lock().unwrap().clone()
+	}
+
+	/// Returns the amount of queued responses that haven't been served yet
+	pub fn remaining(&self) -> usize {
+		self.responses.lock().unwrap().len()
+	}
+}
+
+impl Transport for MockTransport {
+	fn request(&self, req: Request<Body>) -> TransportFuture {
+		let (parts, body) = req.into_parts();
+		let response = self.responses.lock().unwrap().pop_front();
+		let body = match body.concat2().wait() {
+			Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+			Err(e) => return Box::new(::futures::future::err(Error::from(e))),
+		};
+
+		trace!("Mock transport got request {} {}", parts.method, parts.uri);
+		let request = MockRequest {
+			method: parts.method,
+			uri: parts.uri,
+			headers: parts.headers,
+			body,
+		};
+		let result = if let Some(response) = response {
+			let mut builder = Response::builder();
+			builder.status(response.status);
+			for (name, value) in &response.headers {
+				builder.header(name, value.clone());
+			}
+			builder.body(Body::from(response.body)).map_err(Error::from)
+		} else {
+			Err(format_err!("Mock transport has no response queued for {} {}", request.method, request.uri))
+		};
+		self.requests.lock().unwrap().push(request);
+
+		Box::new(::futures::future::result(result))
+	}
+}